@@ -0,0 +1,296 @@
+use std::f32::consts::TAU;
+
+use crate::models::{ArcDescription, Movement, Vec2D};
+
+// how far a flattened arc may stray from the true curve, in mm
+pub const FLATTEN_TOLERANCE: f32 = 0.05;
+
+// for jobs that ask for arc fitting on a machine that doesn't configure a tolerance
+pub const FIT_TOLERANCE: f32 = 0.02;
+
+// any shorter and the arc costs about as much to send as the lines it replaces
+const MIN_FIT_SEGMENTS: usize = 3;
+
+fn angle(v: Vec2D) -> f32 {
+    v.y.atan2(v.x)
+}
+
+/// Signed angle swept going from `start` to `end` around `center`. Positive is
+/// counter-clockwise. Coincident endpoints mean a full circle.
+pub fn sweep(start: Vec2D, end: Vec2D, center: Vec2D, clockwise: bool) -> f32 {
+    let mut sweep = angle(end - center) - angle(start - center);
+    if clockwise {
+        while sweep >= 0.0 {
+            sweep -= TAU;
+        }
+    } else {
+        while sweep <= 0.0 {
+            sweep += TAU;
+        }
+    }
+    // floating point can leave a full circle a hair short of a turn
+    let full = if clockwise { -TAU } else { TAU };
+    if (end - start).length() < 1e-4 {
+        full
+    } else {
+        sweep
+    }
+}
+
+/// Breaks an arc into points (excluding `start`, including `end`) no further than
+/// `tolerance` from the curve. If the endpoints sit at slightly different radii, the
+/// radius is blended between them the same way controllers do.
+pub fn flatten(
+    start: Vec2D,
+    end: Vec2D,
+    center: Vec2D,
+    clockwise: bool,
+    tolerance: f32,
+) -> Vec<Vec2D> {
+    let start_radius = (start - center).length();
+    let end_radius = (end - center).length();
+    let radius = start_radius.max(end_radius);
+    let sweep = sweep(start, end, center, clockwise);
+
+    if radius <= tolerance {
+        return vec![end];
+    }
+
+    // the sagitta of each chord stays under the tolerance
+    let max_step = 2.0 * (1.0 - tolerance / radius).acos();
+    let steps = ((sweep.abs() / max_step).ceil() as usize).max(1);
+    let start_angle = angle(start - center);
+
+    let mut points: Vec<Vec2D> = (1..steps)
+        .map(|step| {
+            let t = step as f32 / steps as f32;
+            let theta = start_angle + sweep * t;
+            let r = start_radius + (end_radius - start_radius) * t;
+            Vec2D {
+                x: center.x + r * theta.cos(),
+                y: center.y + r * theta.sin(),
+            }
+        })
+        .collect();
+    points.push(end);
+    points
+}
+
+/// The point a radius-form arc is centred on. Positive radii take the shorter way
+/// round, negative the longer, as with `G2 R`.
+pub fn center_from_radius(start: Vec2D, end: Vec2D, radius: f32, clockwise: bool) -> Vec2D {
+    let chord = end - start;
+    let half = chord.length() / 2.0;
+    let midpoint = Vec2D {
+        x: start.x + chord.x / 2.0,
+        y: start.y + chord.y / 2.0,
+    };
+    if half < 1e-6 {
+        return midpoint;
+    }
+    // too short a radius to reach; the best we can do is a half circle
+    let offset = (radius * radius - half * half).max(0.0).sqrt();
+    let normal = Vec2D {
+        x: -chord.y / (2.0 * half),
+        y: chord.x / (2.0 * half),
+    };
+    // the center sits to the right of the chord for a short clockwise arc
+    let side = if clockwise == (radius > 0.0) {
+        -1.0
+    } else {
+        1.0
+    };
+    Vec2D {
+        x: midpoint.x + side * offset * normal.x,
+        y: midpoint.y + side * offset * normal.y,
+    }
+}
+
+/// Replaces runs of straight pen-down moves that lie on a circle, to within `tolerance`,
+/// with single arcs. Movements must be absolute, starting from `start`.
+pub fn fit(movements: &[Movement], start: Vec2D, tolerance: f32) -> Vec<Movement> {
+    let mut fitted = Vec::new();
+    let mut position = start;
+    let mut index = 0;
+
+    while index < movements.len() {
+        let mv = &movements[index];
+        let mut best: Option<(usize, ArcDescription)> = None;
+
+        if mv.pen_down && mv.arc.is_none() {
+            let mut points = vec![position];
+            for (offset, next) in movements[index..].iter().enumerate() {
+                if !next.pen_down || next.arc.is_some() || next.pen != mv.pen {
+                    break;
+                }
+                points.push(next.dest);
+                if points.len() <= MIN_FIT_SEGMENTS {
+                    continue;
+                }
+                // the first point off the circle ends the run
+                match fit_circle(&points, tolerance) {
+                    Some(arc) => best = Some((offset, arc)),
+                    None => break,
+                }
+            }
+        }
+
+        match best {
+            Some((offset, arc)) => {
+                let last = &movements[index + offset];
+                fitted.push(Movement {
+                    dest: last.dest,
+                    pen_down: true,
+                    arc: Some(arc),
+                    pen: last.pen.clone(),
+                });
+                position = last.dest;
+                index += offset + 1;
+            }
+            None => {
+                fitted.push(mv.clone());
+                position = mv.dest;
+                index += 1;
+            }
+        }
+    }
+
+    fitted
+}
+
+// The arc through the first, middle and last points, if every point and every
+// segment's midpoint stays within `tolerance` of it
+fn fit_circle(points: &[Vec2D], tolerance: f32) -> Option<ArcDescription> {
+    let origin = points[0];
+    // working relative to the first point keeps the squares small enough for f32
+    let b = points[points.len() / 2] - origin;
+    let c = points[points.len() - 1] - origin;
+
+    let d = 2.0 * b.cross(c);
+    if d.abs() < f32::EPSILON {
+        return None;
+    }
+    let center = Vec2D {
+        x: (c.y * b.dot(b) - b.y * c.dot(c)) / d,
+        y: (b.x * c.dot(c) - c.x * b.dot(b)) / d,
+    };
+    let radius = center.length();
+
+    // nearly straight runs are better left as lines than sent as enormous arcs
+    let chord = c.length();
+    if chord > tolerance
+        && points
+            .iter()
+            .all(|p| (c.cross(*p - origin) / chord).abs() <= tolerance)
+    {
+        return None;
+    }
+
+    let on_circle = |p: Vec2D| ((p - origin - center).length() - radius).abs() <= tolerance;
+    let mut sweep = 0.0;
+    for pair in points.windows(2) {
+        let midpoint = Vec2D {
+            x: (pair[0].x + pair[1].x) / 2.0,
+            y: (pair[0].y + pair[1].y) / 2.0,
+        };
+        if !on_circle(pair[1]) || !on_circle(midpoint) {
+            return None;
+        }
+        let from = pair[0] - origin - center;
+        let to = pair[1] - origin - center;
+        let step = from.cross(to).atan2(from.dot(to));
+        // every segment has to turn the same way
+        if sweep != 0.0 && step.signum() != f32::signum(sweep) {
+            return None;
+        }
+        sweep += step;
+    }
+
+    // a closed loop is ambiguous as a single arc, so stop short of one
+    if sweep.abs() >= TAU - 1e-3 {
+        return None;
+    }
+
+    Some(ArcDescription {
+        clockwise: sweep < 0.0,
+        center: origin + center,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: Vec2D = Vec2D { x: 0.0, y: 0.0 };
+
+    // points every `step` degrees round a circle of radius 10 about the origin
+    fn circle_points(from: i32, to: i32, step: i32) -> Vec<Vec2D> {
+        let step = if to < from { -step } else { step };
+        (0..=(to - from) / step)
+            .map(|i| {
+                let theta = ((from + i * step) as f32).to_radians();
+                Vec2D {
+                    x: 10.0 * theta.cos(),
+                    y: 10.0 * theta.sin(),
+                }
+            })
+            .collect()
+    }
+
+    fn drawn(points: &[Vec2D]) -> Vec<Movement> {
+        points
+            .iter()
+            .map(|point| Movement {
+                dest: *point,
+                pen_down: true,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fitted_arcs_turn_the_right_way() {
+        for (from, to, clockwise) in [(0, 90, false), (90, 0, true)] {
+            let points = circle_points(from, to, 10);
+            let fitted = fit(&drawn(&points[1..]), points[0], 0.05);
+            assert_eq!(fitted.len(), 1);
+            let arc = fitted[0].arc.unwrap();
+            assert_eq!(arc.clockwise, clockwise);
+            assert!(arc.center.length() < 0.05);
+        }
+    }
+
+    #[test]
+    fn full_circles() {
+        let start = Vec2D { x: 10.0, y: 0.0 };
+        assert_eq!(sweep(start, start, ORIGIN, false), TAU);
+        assert_eq!(sweep(start, start, ORIGIN, true), -TAU);
+
+        let points = flatten(start, start, ORIGIN, true, 0.05);
+        assert!(points.len() > 4);
+        assert_eq!(points.last(), Some(&start));
+        // clockwise from (10, 0) heads down first
+        assert!(points[0].y < 0.0);
+
+        // a closed loop isn't sent as one arc
+        let points = circle_points(0, 360, 10);
+        let fitted = fit(&drawn(&points[1..]), points[0], 0.05);
+        assert!(fitted.len() > 1);
+    }
+
+    #[test]
+    fn flattening_stays_within_tolerance() {
+        let start = Vec2D { x: 10.0, y: 0.0 };
+        let end = Vec2D { x: -10.0, y: 0.0 };
+        for tolerance in [0.5, 0.05, 0.005] {
+            let points = flatten(start, end, ORIGIN, false, tolerance);
+            let mut previous = start;
+            for point in points {
+                assert!((point.length() - 10.0).abs() < 1e-3);
+                let midpoint = previous.lerp(point, 0.5);
+                assert!(10.0 - midpoint.length() <= tolerance + 1e-4);
+                previous = point;
+            }
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::models::{Actuator, PenProfile};
+use crate::{ArcCenter, GCode, Position, RenderError, StepperState, Units, Vec3};
+
+/// Things a controller may or may not be able to do, so callers can check before
+/// handing it an instruction it would refuse to render.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Capabilities {
+    pub per_axis_homing: bool,
+    pub per_axis_steppers: bool,
+    pub dwell_in_milliseconds: bool,
+    // whether a line of bare axis words repeats the last G0/G1
+    pub modal_motion: bool,
+    // G2/G3; without them arcs have to be sent as line segments
+    pub arcs: bool,
+    // some controllers error on comment lines, so they have to be stripped before sending
+    pub comments: bool,
+}
+
+/// Everything that differs between one controller's G-code and another's. The
+/// per-instruction methods default to the most common spelling, so a new dialect
+/// only has to override what its firmware does differently.
+pub trait Dialect: Send + Sync {
+    fn name(&self) -> &str;
+    fn capabilities(&self) -> Capabilities;
+    fn default_pen(&self) -> PenProfile;
+
+    fn preamble(&self) -> Vec<GCode> {
+        vec![
+            GCode::SetUnits(Units::Millimeters),
+            GCode::SetPositionMode(Position::Absolute),
+            GCode::Deactivate,
+            GCode::LinearMove {
+                target: Vec3 {
+                    x: None,
+                    y: None,
+                    z: None,
+                },
+                feedrate: Some(1000),
+            },
+            // leave Z alone so the pen heights stay meaningful
+            GCode::SetCurrentPosition(Vec3 {
+                x: Some(0.0),
+                y: Some(0.0),
+                z: None,
+            }),
+        ]
+    }
+
+    fn footer(&self) -> Vec<GCode> {
+        vec![
+            GCode::Deactivate,
+            GCode::LinearMove {
+                target: Vec3 {
+                    x: Some(0.0),
+                    y: Some(0.0),
+                    z: None,
+                },
+                feedrate: None,
+            },
+            GCode::EndProgram,
+        ]
+    }
+
+    fn activate(&self, pen: &PenProfile) -> Result<String, RenderError> {
+        self.move_pen(pen, true)
+    }
+
+    fn deactivate(&self, pen: &PenProfile) -> Result<String, RenderError> {
+        self.move_pen(pen, false)
+    }
+
+    fn move_pen(&self, pen: &PenProfile, lowered: bool) -> Result<String, RenderError> {
+        let value = if lowered { pen.down } else { pen.up };
+        let mut lines: Vec<String> = vec![match &pen.actuator {
+            Actuator::Spindle => format!("M3 S{}", value),
+            Actuator::ZAxis => {
+                let target = Vec3 {
+                    x: None,
+                    y: None,
+                    z: Some(value),
+                };
+                // G0 ignores feedrates on most controllers, so a gentle lift has to be G1
+                match pen.lift_speed {
+                    Some(speed) => self.linear_draw(target, Some(speed))?,
+                    None => self.linear_move(target, None)?,
+                }
+            }
+            Actuator::Custom { up, down } => {
+                if lowered {
+                    down.clone()
+                } else {
+                    up.clone()
+                }
+            }
+        }];
+        if pen.settle_ms > 0 {
+            lines.push(self.pause(pen.settle_ms)?);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn end_program(&self) -> Result<String, RenderError> {
+        Ok("M2".to_string())
+    }
+
+    fn home(&self, x: bool, y: bool, z: bool) -> Result<String, RenderError> {
+        let mut parts: Vec<&str> = vec!["G28"];
+        if x {
+            parts.push("X")
+        };
+        if y {
+            parts.push("Y")
+        };
+        if z {
+            parts.push("Z")
+        };
+
+        Ok(parts.join(" "))
+    }
+
+    fn stepper_control(
+        &self,
+        x: StepperState,
+        y: StepperState,
+        z: StepperState,
+    ) -> Result<String, RenderError> {
+        let mut enable: Vec<&str> = vec!["M17"];
+        let mut disable: Vec<&str> = vec!["M18"];
+
+        for (axis, state) in [("X", x), ("Y", y), ("Z", z)] {
+            match state {
+                StepperState::Enabled => enable.push(axis),
+                StepperState::Disabled => disable.push(axis),
+            }
+        }
+
+        let mut lines: Vec<String> = Vec::new();
+
+        if disable.len() > 1 {
+            lines.push(disable.join(" "))
+        }
+        if enable.len() > 1 {
+            lines.push(enable.join(" "))
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    fn pause(&self, ms: u32) -> Result<String, RenderError> {
+        Ok(format!("G4 P{}", (ms as f32) / 1000.0))
+    }
+
+    fn set_xy(&self) -> Result<String, RenderError> {
+        Ok("G17".to_string())
+    }
+
+    fn set_position_mode(&self, mode: Position) -> Result<String, RenderError> {
+        Ok(match mode {
+            Position::Absolute => "G90",
+            Position::Relative => "G91",
+        }
+        .to_string())
+    }
+
+    fn set_current_position(&self, current: Vec3) -> Result<String, RenderError> {
+        if current.is_empty() {
+            return Err(RenderError::EmptyTarget(GCode::SetCurrentPosition(current)));
+        }
+        Ok(format!("G92 {}", current.to_string()?))
+    }
+
+    fn set_units(&self, units: Units) -> Result<String, RenderError> {
+        Ok(match units {
+            Units::Inches => "G20",
+            Units::Millimeters => "G21",
+        }
+        .to_string())
+    }
+
+    fn linear_move(&self, target: Vec3, feedrate: Option<u32>) -> Result<String, RenderError> {
+        linear("G0", target, feedrate)?.ok_or(RenderError::EmptyTarget(GCode::LinearMove {
+            target,
+            feedrate,
+        }))
+    }
+
+    fn linear_draw(&self, target: Vec3, feedrate: Option<u32>) -> Result<String, RenderError> {
+        linear("G1", target, feedrate)?.ok_or(RenderError::EmptyTarget(GCode::LinearDraw {
+            target,
+            feedrate,
+        }))
+    }
+
+    fn arc(
+        &self,
+        clockwise: bool,
+        target: Vec3,
+        center: ArcCenter,
+        feedrate: Option<u32>,
+    ) -> Result<String, RenderError> {
+        let mut parts: Vec<String> = vec![if clockwise { "G2" } else { "G3" }.to_string()];
+        if let Some(feedrate) = feedrate {
+            parts.push(format!("F{}", feedrate))
+        }
+        let words: Vec<(char, f32)> = match center {
+            ArcCenter::Offset { i, j } => vec![('I', i), ('J', j)],
+            ArcCenter::Radius(r) => {
+                // with no target the radius form has nothing to go on
+                if target.is_empty() {
+                    let gcode = if clockwise {
+                        GCode::ClockwiseArc {
+                            target,
+                            center,
+                            feedrate,
+                        }
+                    } else {
+                        GCode::CounterClockwiseArc {
+                            target,
+                            center,
+                            feedrate,
+                        }
+                    };
+                    return Err(RenderError::EmptyTarget(gcode));
+                }
+                vec![('R', r)]
+            }
+        };
+        if !target.is_empty() {
+            parts.push(target.to_string()?);
+        }
+        for (axis, value) in words {
+            if !value.is_finite() {
+                return Err(RenderError::NonFinite { axis, value });
+            }
+            parts.push(format!("{}{}", axis, value + 0.0));
+        }
+        Ok(parts.join(" "))
+    }
+
+    fn comment(&self, text: &str) -> Result<String, RenderError> {
+        Ok(format!("; {}", text.replace(['\r', '\n'], " ")))
+    }
+
+    fn tool_change(&self, pen: &str) -> Result<String, RenderError> {
+        park_and_wait(self, pen)
+    }
+
+    /// Whether sending this line leaves the controller waiting on the operator
+    fn is_tool_change(&self, line: &str) -> bool {
+        line.lines().any(|line| {
+            let word = line.split_whitespace().next().unwrap_or_default();
+            ["M0", "M00", "M600"].contains(&word.to_ascii_uppercase().as_str())
+        })
+    }
+
+    /// Sent as-is to carry on after the operator has changed the pen
+    fn resume_tool_change(&self) -> String {
+        "M108\n".to_string()
+    }
+
+    fn raw(&self, line: &str) -> Result<String, RenderError> {
+        Ok(line.to_string())
+    }
+
+    fn render(&self, gcode: &GCode, pen: &PenProfile) -> Result<String, RenderError> {
+        match gcode {
+            GCode::Activate => self.activate(pen),
+            GCode::Deactivate => self.deactivate(pen),
+            GCode::EndProgram => self.end_program(),
+            GCode::SetXY => self.set_xy(),
+            GCode::Pause(ms) => self.pause(*ms),
+            GCode::SetPositionMode(mode) => self.set_position_mode(*mode),
+            GCode::SetCurrentPosition(current) => self.set_current_position(*current),
+            GCode::SetUnits(units) => self.set_units(*units),
+            GCode::LinearMove { target, feedrate } => self.linear_move(*target, *feedrate),
+            GCode::LinearDraw { target, feedrate } => self.linear_draw(*target, *feedrate),
+            GCode::ClockwiseArc {
+                target,
+                center,
+                feedrate,
+            } => self.arc(true, *target, *center, *feedrate),
+            GCode::CounterClockwiseArc {
+                target,
+                center,
+                feedrate,
+            } => self.arc(false, *target, *center, *feedrate),
+            GCode::Home { x, y, z } => self.home(*x, *y, *z),
+            GCode::StepperControl { x, y, z } => self.stepper_control(*x, *y, *z),
+            GCode::Comment(text) => self.comment(text),
+            GCode::ToolChange(pen) => self.tool_change(pen),
+            GCode::Raw(line) => self.raw(line),
+        }
+    }
+
+    fn render_all(
+        &self,
+        operations: &[GCode],
+        pen: &PenProfile,
+    ) -> Result<Vec<String>, RenderError> {
+        operations.iter().map(|op| self.render(op, pen)).collect()
+    }
+}
+
+// None if there's nothing to say: a bare feedrate is fine, a move to nowhere isn't
+fn linear(word: &str, target: Vec3, feedrate: Option<u32>) -> Result<Option<String>, RenderError> {
+    if target.is_empty() && feedrate.is_none() {
+        return Ok(None);
+    }
+    let mut parts: Vec<String> = Vec::new();
+    parts.push(word.to_string());
+    if let Some(feedrate) = feedrate {
+        parts.push(format!("F{}", feedrate))
+    }
+    if !target.is_empty() {
+        parts.push(target.to_string()?);
+    }
+    Ok(Some(parts.join(" ")))
+}
+
+// what a tool change's comment says before the pen's name
+pub(crate) const LOAD_PEN_COMMENT: &str = "load pen ";
+
+// the pen is already up by the time this runs; get it out of the way and stop
+fn park_and_wait<D: Dialect + ?Sized>(dialect: &D, pen: &str) -> Result<String, RenderError> {
+    let mut lines: Vec<String> = vec![dialect.linear_move(
+        Vec3 {
+            x: Some(0.0),
+            y: Some(0.0),
+            z: None,
+        },
+        None,
+    )?];
+    if dialect.capabilities().comments {
+        lines.push(dialect.comment(&format!("{LOAD_PEN_COMMENT}{pen}"))?);
+    }
+    lines.push("M0".to_string());
+    Ok(lines.join("\n"))
+}
+
+fn unsupported(dialect: &dyn Dialect, instruction: GCode) -> RenderError {
+    RenderError::Unsupported {
+        instruction,
+        dialect: dialect.name().to_string(),
+    }
+}
+
+pub struct Grbl;
+
+impl Dialect for Grbl {
+    fn name(&self) -> &str {
+        "GRBL"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            per_axis_homing: false,
+            per_axis_steppers: false,
+            dwell_in_milliseconds: false,
+            modal_motion: true,
+            arcs: true,
+            comments: true,
+        }
+    }
+
+    // a servo on the spindle output, with a moment for it to swing into place
+    fn default_pen(&self) -> PenProfile {
+        PenProfile {
+            name: "default".to_string(),
+            actuator: Actuator::Spindle,
+            up: 65.0,
+            down: 254.0,
+            settle_ms: 300,
+            lift_speed: None,
+            color: None,
+        }
+    }
+
+    fn end_program(&self) -> Result<String, RenderError> {
+        Ok("M5\nM2".to_string())
+    }
+
+    fn home(&self, _x: bool, _y: bool, _z: bool) -> Result<String, RenderError> {
+        Ok("$H".to_string())
+    }
+
+    // M0 puts GRBL in a feed hold, which only the cycle start character releases
+    fn resume_tool_change(&self) -> String {
+        "~".to_string()
+    }
+
+    // older GRBL builds only understand parentheses, which can't nest
+    fn comment(&self, text: &str) -> Result<String, RenderError> {
+        Ok(format!(
+            "({})",
+            text.replace(['\r', '\n'], " ")
+                .replace('(', "[")
+                .replace(')', "]")
+        ))
+    }
+
+    // GRBL can't address axes individually; the closest it gets is holding every
+    // motor on forever or putting the whole controller to sleep
+    fn stepper_control(
+        &self,
+        x: StepperState,
+        y: StepperState,
+        z: StepperState,
+    ) -> Result<String, RenderError> {
+        match (x, y, z) {
+            (StepperState::Enabled, StepperState::Enabled, StepperState::Enabled) => {
+                Ok("$1=255".to_string())
+            }
+            (StepperState::Disabled, StepperState::Disabled, StepperState::Disabled) => {
+                Ok("$SLP".to_string())
+            }
+            _ => Err(unsupported(self, GCode::StepperControl { x, y, z })),
+        }
+    }
+}
+
+// Marlin machines usually lift the pen on the Z axis; these are the absolute Z heights
+// for the default pen
+pub struct Marlin {
+    pub pen_up: f32,
+    pub pen_down: f32,
+    // use M600, which parks by itself and prompts on the LCD, instead of a plain M0
+    pub filament_change: bool,
+}
+
+impl Dialect for Marlin {
+    fn name(&self) -> &str {
+        "Marlin"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            per_axis_homing: true,
+            per_axis_steppers: true,
+            dwell_in_milliseconds: true,
+            modal_motion: false,
+            arcs: true,
+            comments: true,
+        }
+    }
+
+    fn default_pen(&self) -> PenProfile {
+        PenProfile {
+            name: "default".to_string(),
+            actuator: Actuator::ZAxis,
+            up: self.pen_up,
+            down: self.pen_down,
+            settle_ms: 0,
+            lift_speed: None,
+            color: None,
+        }
+    }
+
+    fn tool_change(&self, pen: &str) -> Result<String, RenderError> {
+        if self.filament_change {
+            Ok("M600".to_string())
+        } else {
+            park_and_wait(self, pen)
+        }
+    }
+
+    // Marlin has no M2; wait for the planner to drain, then release the motors
+    fn end_program(&self) -> Result<String, RenderError> {
+        Ok("M400\nM84".to_string())
+    }
+
+    // Marlin's G4 P is already in milliseconds, and only blocks the parser, so let
+    // queued moves finish first
+    fn pause(&self, ms: u32) -> Result<String, RenderError> {
+        Ok(format!("M400\nG4 P{}", ms))
+    }
+}
+
+type Constructor = Box<dyn Fn(&HashMap<String, String>) -> Arc<dyn Dialect> + Send + Sync>;
+
+/// Maps the `flavor` names used in `machine_settings.toml` to dialects. Downstream
+/// crates can register their own before loading the machine config.
+pub struct DialectRegistry {
+    constructors: HashMap<String, Constructor>,
+}
+
+impl DialectRegistry {
+    pub fn empty() -> Self {
+        DialectRegistry {
+            constructors: HashMap::new(),
+        }
+    }
+
+    /// The constructor gets the full machine config, so dialects can read their own keys
+    pub fn register<F>(&mut self, name: &str, constructor: F)
+    where
+        F: Fn(&HashMap<String, String>) -> Arc<dyn Dialect> + Send + Sync + 'static,
+    {
+        self.constructors
+            .insert(name.to_string(), Box::new(constructor));
+    }
+
+    pub fn build(
+        &self,
+        name: &str,
+        settings: &HashMap<String, String>,
+    ) -> Option<Arc<dyn Dialect>> {
+        self.constructors
+            .get(name)
+            .map(|constructor| constructor(settings))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.constructors.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+impl Default for DialectRegistry {
+    fn default() -> Self {
+        let mut registry = DialectRegistry::empty();
+        registry.register("GRBL", |_| Arc::new(Grbl));
+        registry.register("Marlin", |settings| {
+            Arc::new(Marlin {
+                pen_up: settings
+                    .get("pen_up_z")
+                    .map_or(5.0, |z| z.parse().expect("Invalid config value: pen_up_z")),
+                pen_down: settings.get("pen_down_z").map_or(0.0, |z| {
+                    z.parse().expect("Invalid config value: pen_down_z")
+                }),
+                filament_change: settings.get("filament_change").is_some_and(|change| {
+                    change
+                        .parse()
+                        .expect("Invalid config value: filament_change")
+                }),
+            })
+        });
+        registry
+    }
+}
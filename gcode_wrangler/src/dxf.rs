@@ -0,0 +1,483 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::models::{ArcDescription, Layer, Movement, Vec2D};
+use crate::transform::Transform;
+
+// curves are split into at least this many pieces before checking how flat they are,
+// so an S bend can't pass for a straight line
+const MIN_CURVE_PIECES: usize = 16;
+
+// and each piece is split in half at most this many times
+const MAX_SUBDIVISIONS: u32 = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum DxfError {
+    Binary,
+    // a group code that isn't a number, or one with no value after it
+    Malformed { line: usize },
+    // an $INSUNITS with no sensible size on a bed
+    UnknownUnits(i32),
+}
+
+impl fmt::Display for DxfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DxfError::Binary => write!(f, "Binary DXF isn't supported; export it as ASCII"),
+            DxfError::Malformed { line } => write!(f, "Malformed DXF at line {line}"),
+            DxfError::UnknownUnits(units) => write!(f, "Unsupported $INSUNITS {units}"),
+        }
+    }
+}
+
+impl std::error::Error for DxfError {}
+
+// millimetres in one of an $INSUNITS unit; unitless drawings are taken as millimetres
+fn millimetres(units: i32) -> Result<f32, DxfError> {
+    Ok(match units {
+        0 | 4 => 1.0,
+        1 => 25.4,
+        2 => 304.8,
+        5 => 10.0,
+        6 => 1000.0,
+        8 => 0.0000254,
+        9 => 0.0254,
+        10 => 914.4,
+        13 => 0.001,
+        14 => 100.0,
+        other => return Err(DxfError::UnknownUnits(other)),
+    })
+}
+
+/// One entity's group codes and values, in file order
+struct Entity<'a> {
+    kind: &'a str,
+    codes: Vec<(i32, &'a str)>,
+}
+
+impl Entity<'_> {
+    fn get(&self, code: i32) -> Option<f64> {
+        self.codes
+            .iter()
+            .find(|(c, _)| *c == code)
+            .and_then(|(_, value)| value.parse().ok())
+    }
+
+    fn number(&self, code: i32) -> f64 {
+        self.get(code).unwrap_or(0.0)
+    }
+
+    fn all(&self, code: i32) -> Vec<f64> {
+        self.codes
+            .iter()
+            .filter(|(c, _)| *c == code)
+            .filter_map(|(_, value)| value.parse().ok())
+            .collect()
+    }
+
+    fn layer(&self) -> &str {
+        self.codes
+            .iter()
+            .find(|(c, _)| *c == 8)
+            .map_or("0", |(_, value)| value)
+    }
+
+    fn flags(&self) -> i64 {
+        self.get(70).map_or(0, |flags| flags as i64)
+    }
+
+    // Entities drawn on the underside have their X axis flipped; tilted ones aren't
+    // going to come out right on a flat bed whatever happens.
+    fn mirrored(&self) -> bool {
+        self.get(230).is_some_and(|z| z < 0.0)
+    }
+}
+
+// Group codes and their values, up to the EOF marker. Whatever follows it, like the
+// blank line some exporters end with, is ignored.
+fn pairs(source: &str) -> Result<Vec<(i32, &str)>, DxfError> {
+    let mut lines = source.lines().enumerate();
+    let mut pairs: Vec<(i32, &str)> = Vec::new();
+
+    while let Some((index, code)) = lines.next() {
+        // a file without EOF can still end in blank lines
+        if code.trim().is_empty() && lines.clone().all(|(_, line)| line.trim().is_empty()) {
+            break;
+        }
+        let code: i32 = code
+            .trim()
+            .parse()
+            .map_err(|_| DxfError::Malformed { line: index + 1 })?;
+        let value = match lines.next() {
+            Some((_, value)) => value.trim(),
+            None => return Err(DxfError::Malformed { line: index + 1 }),
+        };
+        pairs.push((code, value));
+        if (code, value) == (0, "EOF") {
+            break;
+        }
+    }
+
+    Ok(pairs)
+}
+
+// The value after $INSUNITS in the header, and the entities section split into
+// entities. Entities inside blocks are only drawn where they're inserted, which isn't
+// supported, so they're left out.
+fn sections<'a>(pairs: &[(i32, &'a str)]) -> (i32, Vec<Entity<'a>>) {
+    let mut units = 0;
+    let mut entities: Vec<Entity> = Vec::new();
+    let mut section: Option<&str> = None;
+    let mut previous: Option<(i32, &str)> = None;
+
+    for (i, &(code, value)) in pairs.iter().enumerate() {
+        match (code, value) {
+            (2, name) if previous == Some((0, "SECTION")) => section = Some(name),
+            (0, "ENDSEC") => section = None,
+            (9, "$INSUNITS") if section == Some("HEADER") => {
+                if let Some((70, value)) = pairs.get(i + 1) {
+                    units = value.parse().unwrap_or(0);
+                }
+            }
+            (0, kind) if section == Some("ENTITIES") => entities.push(Entity {
+                kind,
+                codes: Vec::new(),
+            }),
+            _ if section == Some("ENTITIES") => {
+                if let Some(entity) = entities.last_mut() {
+                    entity.codes.push((code, value));
+                }
+            }
+            _ => (),
+        }
+        previous = Some((code, value));
+    }
+
+    (units, entities)
+}
+
+fn point(x: f64, y: f64) -> Vec2D {
+    Vec2D {
+        x: x as f32,
+        y: y as f32,
+    }
+}
+
+fn travel(dest: Vec2D) -> Movement {
+    Movement {
+        dest,
+        pen_down: false,
+        ..Default::default()
+    }
+}
+
+fn draw(dest: Vec2D) -> Movement {
+    Movement {
+        dest,
+        pen_down: true,
+        ..Default::default()
+    }
+}
+
+fn arc(dest: Vec2D, center: Vec2D, clockwise: bool) -> Movement {
+    Movement {
+        dest,
+        pen_down: true,
+        arc: Some(ArcDescription { clockwise, center }),
+        pen: None,
+    }
+}
+
+// Points along `curve` from `start` to `end`, not including the first, close enough
+// together that straight lines between them stay within `tolerance` of it
+fn sample(curve: impl Fn(f64) -> (f64, f64), start: f64, end: f64, tolerance: f64) -> Vec<Vec2D> {
+    fn split(
+        curve: &impl Fn(f64) -> (f64, f64),
+        from: f64,
+        to: f64,
+        tolerance: f64,
+        depth: u32,
+        points: &mut Vec<Vec2D>,
+    ) {
+        let (a, b) = (curve(from), curve(to));
+        let middle = (from + to) / 2.0;
+        let m = curve(middle);
+        let off = ((m.0 - (a.0 + b.0) / 2.0).powi(2) + (m.1 - (a.1 + b.1) / 2.0).powi(2)).sqrt();
+        if off > tolerance && depth < MAX_SUBDIVISIONS {
+            split(curve, from, middle, tolerance, depth + 1, points);
+            split(curve, middle, to, tolerance, depth + 1, points);
+        } else {
+            points.push(point(b.0, b.1));
+        }
+    }
+
+    let mut points: Vec<Vec2D> = Vec::new();
+    let step = (end - start) / MIN_CURVE_PIECES as f64;
+    for piece in 0..MIN_CURVE_PIECES {
+        let from = start + step * piece as f64;
+        split(&curve, from, from + step, tolerance, 0, &mut points);
+    }
+    points
+}
+
+// A point on a B-spline, by de Boor's algorithm, with weights for rational ones
+fn de_boor(t: f64, degree: usize, knots: &[f64], controls: &[(f64, f64, f64)]) -> (f64, f64) {
+    let n = controls.len();
+    let span = (degree..n).rev().find(|k| knots[*k] <= t).unwrap_or(degree);
+
+    let mut d: Vec<(f64, f64, f64)> = (0..=degree)
+        .map(|j| {
+            let (x, y, w) = controls[j + span - degree];
+            (x * w, y * w, w)
+        })
+        .collect();
+    for r in 1..=degree {
+        for j in (r..=degree).rev() {
+            let low = knots[j + span - degree];
+            let high = knots[j + 1 + span - r];
+            let alpha = if high > low {
+                (t - low) / (high - low)
+            } else {
+                0.0
+            };
+            d[j] = (
+                (1.0 - alpha) * d[j - 1].0 + alpha * d[j].0,
+                (1.0 - alpha) * d[j - 1].1 + alpha * d[j].1,
+                (1.0 - alpha) * d[j - 1].2 + alpha * d[j].2,
+            );
+        }
+    }
+    let (x, y, w) = d[degree];
+    (x / w, y / w)
+}
+
+// The movements for a polyline's vertices, each with the bulge of the segment that
+// leaves it; a bulge is the tangent of a quarter of the arc's angle, negative for
+// clockwise
+fn polyline(vertices: &[(f64, f64, f64)], closed: bool, mirror: f64) -> Vec<Movement> {
+    let mut movements: Vec<Movement> = Vec::new();
+    let (first, rest) = match vertices.split_first() {
+        Some(split) => split,
+        None => return movements,
+    };
+    movements.push(travel(point(first.0 * mirror, first.1)));
+
+    let mut ends: Vec<&(f64, f64, f64)> = rest.iter().collect();
+    if closed {
+        ends.push(first);
+    }
+    let mut from = first;
+    for to in ends {
+        let bulge = from.2;
+        let dest = point(to.0 * mirror, to.1);
+        if bulge.abs() < 1e-9 {
+            movements.push(draw(dest));
+        } else {
+            let (mx, my) = ((from.0 + to.0) / 2.0, (from.1 + to.1) / 2.0);
+            let (cx, cy) = (to.0 - from.0, to.1 - from.1);
+            let offset = (1.0 - bulge * bulge) / (4.0 * bulge);
+            let center = point((mx - cy * offset) * mirror, my + cx * offset);
+            // mirroring turns arcs the other way
+            movements.push(arc(dest, center, (bulge < 0.0) == (mirror > 0.0)));
+        }
+        from = to;
+    }
+    movements
+}
+
+fn entity_movements(entity: &Entity, tolerance: f64) -> Vec<Movement> {
+    let mirror = if entity.mirrored() { -1.0 } else { 1.0 };
+    let at = |x: f64, y: f64| point(x * mirror, y);
+    // DXF arcs always go counter-clockwise, until they're mirrored
+    let clockwise = mirror < 0.0;
+
+    match entity.kind {
+        "LINE" => vec![
+            travel(at(entity.number(10), entity.number(20))),
+            draw(at(entity.number(11), entity.number(21))),
+        ],
+        "CIRCLE" => {
+            let (x, y, r) = (entity.number(10), entity.number(20), entity.number(40));
+            let center = at(x, y);
+            vec![
+                travel(at(x + r, y)),
+                arc(at(x - r, y), center, clockwise),
+                arc(at(x + r, y), center, clockwise),
+            ]
+        }
+        "ARC" => {
+            let (x, y, r) = (entity.number(10), entity.number(20), entity.number(40));
+            let (start, end) = (
+                entity.number(50).to_radians(),
+                entity.number(51).to_radians(),
+            );
+            vec![
+                travel(at(x + r * start.cos(), y + r * start.sin())),
+                arc(
+                    at(x + r * end.cos(), y + r * end.sin()),
+                    at(x, y),
+                    clockwise,
+                ),
+            ]
+        }
+        "LWPOLYLINE" => {
+            let mut vertices: Vec<(f64, f64, f64)> = Vec::new();
+            for (code, value) in &entity.codes {
+                let value: f64 = value.parse().unwrap_or(0.0);
+                match code {
+                    10 => vertices.push((value, 0.0, 0.0)),
+                    20 => {
+                        if let Some(vertex) = vertices.last_mut() {
+                            vertex.1 = value;
+                        }
+                    }
+                    42 => {
+                        if let Some(vertex) = vertices.last_mut() {
+                            vertex.2 = value;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            polyline(&vertices, entity.flags() & 1 != 0, mirror)
+        }
+        "ELLIPSE" => {
+            let (cx, cy) = (entity.number(10), entity.number(20));
+            let (mx, my) = (entity.number(11), entity.number(21));
+            let ratio = entity.get(40).unwrap_or(1.0);
+            let start = entity.number(41);
+            let mut end = entity.get(42).unwrap_or(2.0 * PI);
+            if end <= start {
+                end += 2.0 * PI;
+            }
+            let curve = |t: f64| {
+                (
+                    (cx + mx * t.cos() - ratio * my * t.sin()) * mirror,
+                    cy + my * t.cos() + ratio * mx * t.sin(),
+                )
+            };
+            let (x, y) = curve(start);
+            let mut movements = vec![travel(point(x, y))];
+            movements.extend(sample(curve, start, end, tolerance).into_iter().map(draw));
+            movements
+        }
+        "SPLINE" => {
+            let degree = entity.get(71).unwrap_or(3.0) as usize;
+            let knots = entity.all(40);
+            let weights = entity.all(41);
+            let (xs, ys) = (entity.all(10), entity.all(20));
+            let controls: Vec<(f64, f64, f64)> = xs
+                .iter()
+                .zip(ys.iter())
+                .enumerate()
+                .map(|(i, (x, y))| (*x * mirror, *y, weights.get(i).copied().unwrap_or(1.0)))
+                .collect();
+
+            if controls.len() > degree && knots.len() == controls.len() + degree + 1 {
+                let (start, end) = (knots[degree], knots[controls.len()]);
+                let curve = |t: f64| de_boor(t, degree, &knots, &controls);
+                let (x, y) = curve(start);
+                let mut movements = vec![travel(point(x, y))];
+                movements.extend(sample(curve, start, end, tolerance).into_iter().map(draw));
+                movements
+            } else {
+                // only fit points, which the curve passes through; joined up straight
+                let fit: Vec<(f64, f64, f64)> = entity
+                    .all(11)
+                    .into_iter()
+                    .zip(entity.all(21))
+                    .map(|(x, y)| (x, y, 0.0))
+                    .collect();
+                polyline(&fit, entity.flags() & 1 != 0, mirror)
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Reads a DXF's LINE, ARC, CIRCLE, ELLIPSE, SPLINE, LWPOLYLINE and 2D POLYLINE
+/// entities into absolute movements in millimetres, scaled by `$INSUNITS`, with a
+/// layer for each DXF layer. Circles and arcs, including polyline bulges, stay arcs;
+/// ellipses and splines are flattened to within `tolerance` mm.
+pub fn import(source: &str, tolerance: f32) -> Result<Vec<Layer>, DxfError> {
+    if source.starts_with("AutoCAD Binary DXF") {
+        return Err(DxfError::Binary);
+    }
+    let pairs = pairs(source)?;
+    let (units, entities) = sections(&pairs);
+    let scale = millimetres(units)?;
+    let tolerance = (tolerance / scale) as f64;
+
+    let mut layers: Vec<Layer> = Vec::new();
+    let mut add = |name: &str, movements: Vec<Movement>| {
+        if movements.is_empty() {
+            return;
+        }
+        match layers.iter_mut().find(|layer| layer.name == name) {
+            Some(layer) => layer.movements.extend(movements),
+            None => layers.push(Layer::new(name, movements)),
+        }
+    };
+
+    let mut entities = entities.iter().peekable();
+    while let Some(entity) = entities.next() {
+        if entity.kind != "POLYLINE" {
+            add(entity.layer(), entity_movements(entity, tolerance));
+            continue;
+        }
+        // old-style polylines keep their points in the VERTEX entities after them
+        let vertices: Vec<(f64, f64, f64)> =
+            std::iter::from_fn(|| entities.next_if(|vertex| vertex.kind == "VERTEX"))
+                .map(|vertex| (vertex.number(10), vertex.number(20), vertex.number(42)))
+                .collect();
+        entities.next_if(|end| end.kind == "SEQEND");
+        // 3D polylines and meshes
+        if entity.flags() & (8 | 16 | 64) != 0 {
+            continue;
+        }
+        let mirror = if entity.mirrored() { -1.0 } else { 1.0 };
+        add(
+            entity.layer(),
+            polyline(&vertices, entity.flags() & 1 != 0, mirror),
+        );
+    }
+
+    let scaling = Transform::scale(scale, scale);
+    for layer in layers.iter_mut() {
+        layer.movements = scaling.apply_movements(&layer.movements);
+    }
+    Ok(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = "0\nSECTION\n2\nENTITIES\n0\nLINE\n8\n0\n10\n1\n20\n2\n11\n3\n21\n4\n\
+                        0\nENDSEC\n0\nEOF";
+
+    fn ends(source: &str) -> Vec<Vec2D> {
+        let layers = import(source, 0.1).unwrap();
+        layers[0].movements.iter().map(|mv| mv.dest).collect()
+    }
+
+    #[test]
+    fn real_world_file_endings() {
+        let expected = vec![Vec2D { x: 1.0, y: 2.0 }, Vec2D { x: 3.0, y: 4.0 }];
+        for ending in ["", "\n", "\n\n", "\r\n", "\n  \n", "\nleftovers\n"] {
+            assert_eq!(ends(&format!("{LINE}{ending}")), expected, "{ending:?}");
+        }
+        assert_eq!(ends(&LINE.replace('\n', "\r\n")), expected);
+        // cut off before EOF, but still in whole pairs
+        let cut = LINE.trim_end_matches("\n0\nEOF");
+        assert_eq!(ends(&format!("{cut}\n\n")), expected);
+    }
+
+    #[test]
+    fn odd_line_out() {
+        assert_eq!(
+            import("0\nSECTION\n2", 0.1).err(),
+            Some(DxfError::Malformed { line: 3 })
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use crate::dialect::Dialect;
+use crate::models::PenProfile;
+use crate::{ArcCenter, GCode, Position, RenderError, Vec3};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Rapid,
+    Linear,
+}
+
+// What the controller believes right now, as far as we can tell from what we've sent.
+// None means we don't know, so the next line has to spell it out.
+#[derive(Debug, Clone, Default)]
+struct ModalState {
+    position: Vec3,
+    motion: Option<Motion>,
+    feedrate: Option<u32>,
+    relative: bool,
+}
+
+/// Renders instructions one at a time while tracking modal state, so each line only
+/// says what actually changed.
+pub struct Emitter<'a> {
+    dialect: &'a dyn Dialect,
+    pen: &'a PenProfile,
+    // profiles a tool change can switch to
+    pens: &'a [PenProfile],
+    precision: i32,
+    state: ModalState,
+    // the feedrate the program has asked for, which may not have been sent yet
+    requested_feedrate: Option<u32>,
+    lines: Vec<String>,
+}
+
+impl<'a> Emitter<'a> {
+    pub fn new(dialect: &'a dyn Dialect, pen: &'a PenProfile, precision: usize) -> Self {
+        Emitter {
+            dialect,
+            pen,
+            pens: &[],
+            precision: precision as i32,
+            state: ModalState::default(),
+            requested_feedrate: None,
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn with_pens(self, pens: &'a [PenProfile]) -> Self {
+        Emitter { pens, ..self }
+    }
+
+    pub fn emit_all(&mut self, operations: &[GCode]) -> Result<(), RenderError> {
+        for op in operations {
+            self.emit(op)?;
+        }
+        Ok(())
+    }
+
+    pub fn emit(&mut self, gcode: &GCode) -> Result<(), RenderError> {
+        match gcode {
+            GCode::LinearMove { target, feedrate } => {
+                self.motion(Motion::Rapid, *target, *feedrate)
+            }
+            GCode::LinearDraw { target, feedrate } => {
+                self.motion(Motion::Linear, *target, *feedrate)
+            }
+            GCode::ClockwiseArc {
+                target,
+                center,
+                feedrate,
+            } => self.arc(true, *target, *center, *feedrate),
+            GCode::CounterClockwiseArc {
+                target,
+                center,
+                feedrate,
+            } => self.arc(false, *target, *center, *feedrate),
+            GCode::SetCurrentPosition(position) => {
+                let position = self.quantize(*position);
+                self.render(&GCode::SetCurrentPosition(position))?;
+                self.state.position = Vec3 {
+                    x: position.x.or(self.state.position.x),
+                    y: position.y.or(self.state.position.y),
+                    z: position.z.or(self.state.position.z),
+                };
+                Ok(())
+            }
+            other => {
+                self.render(other)?;
+                match other {
+                    GCode::SetPositionMode(mode) => {
+                        self.state.relative = *mode == Position::Relative
+                    }
+                    // the same numbers mean something else now
+                    GCode::SetUnits(_) => self.state.position = Vec3::default(),
+                    GCode::SetXY | GCode::Pause(_) | GCode::Comment(_) => (),
+                    // the tool change itself is still done with the old pen
+                    GCode::ToolChange(name) => {
+                        if let Some(pen) = self.pens.iter().find(|pen| pen.name == *name) {
+                            self.pen = pen;
+                        }
+                        self.state = ModalState {
+                            relative: self.state.relative,
+                            ..ModalState::default()
+                        }
+                    }
+                    // pens never move X or Y, but a Z axis pen moves Z at its own speed
+                    GCode::Activate | GCode::Deactivate => {
+                        self.state.position.z = None;
+                        self.state.motion = None;
+                        self.state.feedrate = None;
+                    }
+                    // no idea what these did, so assume the worst
+                    _ => {
+                        self.state = ModalState {
+                            relative: self.state.relative,
+                            ..ModalState::default()
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<String> {
+        self.lines
+    }
+
+    fn render(&mut self, gcode: &GCode) -> Result<(), RenderError> {
+        self.lines.push(self.dialect.render(gcode, self.pen)?);
+        Ok(())
+    }
+
+    fn round(&self, value: f32) -> f32 {
+        let scale = 10f32.powi(self.precision);
+        // adding zero turns -0 into 0
+        (value * scale).round() / scale + 0.0
+    }
+
+    fn quantize(&self, target: Vec3) -> Vec3 {
+        Vec3 {
+            x: target.x.map(|x| self.round(x)),
+            y: target.y.map(|y| self.round(y)),
+            z: target.z.map(|z| self.round(z)),
+        }
+    }
+
+    fn motion(
+        &mut self,
+        motion: Motion,
+        target: Vec3,
+        feedrate: Option<u32>,
+    ) -> Result<(), RenderError> {
+        if feedrate.is_some() {
+            self.requested_feedrate = feedrate;
+        }
+        let target = self.quantize(target);
+
+        // in relative mode a repeated value is still a move
+        let needed = if self.state.relative {
+            target
+        } else {
+            let position = self.state.position;
+            Vec3 {
+                x: target.x.filter(|x| position.x != Some(*x)),
+                y: target.y.filter(|y| position.y != Some(*y)),
+                z: target.z.filter(|z| position.z != Some(*z)),
+            }
+        };
+        if needed.is_empty() {
+            // nowhere to go; any new feedrate rides along with the next real move
+            return Ok(());
+        }
+
+        let feedrate = self
+            .requested_feedrate
+            .filter(|f| self.state.feedrate != Some(*f));
+        let gcode = match motion {
+            Motion::Rapid => GCode::LinearMove {
+                target: needed,
+                feedrate,
+            },
+            Motion::Linear => GCode::LinearDraw {
+                target: needed,
+                feedrate,
+            },
+        };
+        let mut rendered = self.dialect.render(&gcode, self.pen)?;
+
+        if self.dialect.capabilities().modal_motion && self.state.motion == Some(motion) {
+            let word = match motion {
+                Motion::Rapid => "G0 ",
+                Motion::Linear => "G1 ",
+            };
+            if let Some(rest) = rendered.strip_prefix(word) {
+                if !rest.contains('\n') {
+                    rendered = rest.to_string();
+                }
+            }
+        }
+        self.lines.push(rendered);
+
+        self.state.motion = Some(motion);
+        self.state.feedrate = feedrate.or(self.state.feedrate);
+        if self.state.relative {
+            self.state.position = Vec3::default();
+        } else {
+            self.state.position = Vec3 {
+                x: target.x.or(self.state.position.x),
+                y: target.y.or(self.state.position.y),
+                z: target.z.or(self.state.position.z),
+            };
+        }
+        Ok(())
+    }
+
+    // the endpoint and center only make sense together, so arcs always spell out the
+    // whole target rather than dropping unchanged axes
+    fn arc(
+        &mut self,
+        clockwise: bool,
+        target: Vec3,
+        center: ArcCenter,
+        feedrate: Option<u32>,
+    ) -> Result<(), RenderError> {
+        if feedrate.is_some() {
+            self.requested_feedrate = feedrate;
+        }
+        let target = self.quantize(target);
+        let center = match center {
+            ArcCenter::Offset { i, j } => ArcCenter::Offset {
+                i: self.round(i),
+                j: self.round(j),
+            },
+            ArcCenter::Radius(r) => ArcCenter::Radius(self.round(r)),
+        };
+
+        let feedrate = self
+            .requested_feedrate
+            .filter(|f| self.state.feedrate != Some(*f));
+        let gcode = if clockwise {
+            GCode::ClockwiseArc {
+                target,
+                center,
+                feedrate,
+            }
+        } else {
+            GCode::CounterClockwiseArc {
+                target,
+                center,
+                feedrate,
+            }
+        };
+        self.render(&gcode)?;
+
+        // a bare line after this would be read as another arc, so never let one through
+        self.state.motion = None;
+        self.state.feedrate = feedrate.or(self.state.feedrate);
+        if self.state.relative {
+            self.state.position = Vec3::default();
+        } else {
+            self.state.position = Vec3 {
+                x: target.x.or(self.state.position.x),
+                y: target.y.or(self.state.position.y),
+                z: target.z.or(self.state.position.z),
+            };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+use crate::arcs;
+use crate::masks::{self, Intervals};
+use crate::models::{Movement, Vec2D};
+
+// how close, in mm, the ends of a stroke have to be for it to count as a closed shape
+const CLOSE_TOLERANCE: f32 = 0.1;
+
+// hatch lines shorter than this, in mm, aren't worth putting the pen down for
+const MIN_HATCH_LENGTH: f32 = 0.1;
+
+/// The most rows of lines one angle of a fill may take, so a tiny spacing can't eat
+/// all the memory there is
+pub const MAX_HATCH_ROWS: u64 = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub enum HatchError {
+    // not a positive number of millimetres
+    BadSpacing(f32),
+    TooManyRows(u64),
+}
+
+impl fmt::Display for HatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HatchError::BadSpacing(spacing) => {
+                write!(f, "fill spacing {spacing} isn't a positive number")
+            }
+            HatchError::TooManyRows(rows) => write!(
+                f,
+                "fill would need {rows} rows of lines, and the most is {MAX_HATCH_ROWS}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HatchError {}
+
+/// How to fill closed shapes with parallel lines. Shapes inside other shapes are holes,
+/// and holes inside those are filled again.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Hatch {
+    // between lines, in mm
+    pub spacing: f32,
+    // of the lines, in degrees counter-clockwise from the X axis
+    #[serde(default)]
+    pub angle: f32,
+    // a second set of lines at right angles to the first
+    #[serde(default)]
+    pub cross: bool,
+    // how far, in mm, lines stop short of every edge
+    #[serde(default)]
+    pub inset: f32,
+}
+
+impl Hash for Hatch {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.spacing.to_le_bytes().hash(state);
+        self.angle.to_le_bytes().hash(state);
+        self.cross.hash(state);
+        self.inset.to_le_bytes().hash(state);
+    }
+}
+
+// turns `point` by the angle whose sine and cosine are given
+fn turn(point: Vec2D, sin: f32, cos: f32) -> Vec2D {
+    Vec2D {
+        x: point.x * cos - point.y * sin,
+        y: point.x * sin + point.y * cos,
+    }
+}
+
+/// Every stroke in absolute movements that ends where it started, as a polygon
+pub fn closed_strokes(movements: &[Movement]) -> Vec<Vec<Vec2D>> {
+    let mut shapes: Vec<Vec<Vec2D>> = Vec::new();
+    let mut stroke: Vec<Vec2D> = Vec::new();
+    let mut position = Vec2D::default();
+
+    let mut close = |mut stroke: Vec<Vec2D>| {
+        if stroke.len() >= 4 && stroke[0].distance(stroke[stroke.len() - 1]) <= CLOSE_TOLERANCE {
+            stroke.pop();
+            shapes.push(stroke);
+        }
+    };
+
+    for mv in movements {
+        if mv.pen_down {
+            if stroke.is_empty() {
+                stroke.push(position);
+            }
+            match mv.arc {
+                Some(arc) => stroke.extend(arcs::flatten(
+                    position,
+                    mv.dest,
+                    arc.center,
+                    arc.clockwise,
+                    arcs::FLATTEN_TOLERANCE,
+                )),
+                None => stroke.push(mv.dest),
+            }
+        } else if !stroke.is_empty() {
+            close(std::mem::take(&mut stroke));
+        }
+        position = mv.dest;
+    }
+    close(stroke);
+
+    shapes
+}
+
+/// What a layer fills: its shapes, or its closed strokes if it has none. A shape's
+/// outline comes along as movements, and filled twice the copies would cancel out.
+pub fn fill_shapes(shapes: Vec<Vec<Vec2D>>, movements: &[Movement]) -> Vec<Vec<Vec2D>> {
+    if shapes.is_empty() {
+        closed_strokes(movements)
+    } else {
+        shapes
+    }
+}
+
+// Where a horizontal line at `y` comes within `reach` of the segment `a`-`b`. That
+// region is convex, so it's the span of wherever the line crosses its parts.
+fn near(a: Vec2D, b: Vec2D, y: f32, reach: f32) -> Option<(f32, f32)> {
+    let mut crossings: Vec<f32> = Vec::new();
+    for end in [a, b] {
+        let dy = y - end.y;
+        if dy.abs() <= reach {
+            let half = (reach * reach - dy * dy).sqrt();
+            crossings.extend([end.x - half, end.x + half]);
+        }
+    }
+
+    let length = a.distance(b);
+    if length > f32::EPSILON {
+        let normal = Vec2D {
+            x: -(b.y - a.y) / length * reach,
+            y: (b.x - a.x) / length * reach,
+        };
+        let corners = [a + normal, b + normal, b - normal, a - normal];
+        for (i, from) in corners.iter().enumerate() {
+            let to = corners[(i + 1) % corners.len()];
+            if (from.y > y) != (to.y > y) {
+                crossings.push(from.x + (y - from.y) / (to.y - from.y) * (to.x - from.x));
+            }
+        }
+    }
+
+    let low = crossings.iter().copied().reduce(f32::min)?;
+    let high = crossings.iter().copied().reduce(f32::max)?;
+    Some((low, high))
+}
+
+// One set of parallel lines across `shapes` at `angle` degrees, going back and forth
+fn lines(
+    shapes: &[Vec<Vec2D>],
+    angle: f32,
+    spacing: f32,
+    inset: f32,
+) -> Result<Vec<(Vec2D, Vec2D)>, HatchError> {
+    // turn everything so the lines run along X, and turn the lines back at the end
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let edges: Vec<(Vec2D, Vec2D)> = shapes
+        .iter()
+        .filter(|shape| shape.len() >= 3)
+        .flat_map(|shape| {
+            let turned: Vec<Vec2D> = shape.iter().map(|p| turn(*p, -sin, cos)).collect();
+            (0..turned.len())
+                .map(|i| (turned[i], turned[(i + 1) % turned.len()]))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let (low, high) = match (
+        edges.iter().map(|(a, _)| a.y).reduce(f32::min),
+        edges.iter().map(|(a, _)| a.y).reduce(f32::max),
+    ) {
+        (Some(low), Some(high)) => (low, high),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut hatched: Vec<(Vec2D, Vec2D)> = Vec::new();
+    // on a grid, so neighbouring shapes filled together have their lines line up
+    let (first, last) = ((low / spacing).floor(), (high / spacing).ceil());
+    // counted as a float, since the row numbers themselves can overflow
+    let rows = last - first + 1.0;
+    if rows.is_nan() || rows > MAX_HATCH_ROWS as f32 {
+        return Err(HatchError::TooManyRows(rows as u64));
+    }
+    let (first, last) = (first as i64, last as i64);
+    for row in first..=last {
+        let y = (row as f32 + 0.5) * spacing;
+
+        // even-odd, like the masks, so holes come out by themselves
+        let mut crossings: Vec<f32> = edges
+            .iter()
+            .filter(|(a, b)| (a.y > y) != (b.y > y))
+            .map(|(a, b)| a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x))
+            .collect();
+        crossings.sort_by(|a, b| a.total_cmp(b));
+        let mut spans: Intervals = crossings
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+
+        if inset > 0.0 {
+            let edging: Intervals = edges
+                .iter()
+                .filter_map(|(a, b)| near(*a, *b, y, inset))
+                .collect();
+            spans = masks::subtract(spans, &masks::union(edging));
+        }
+
+        let mut row_lines: Vec<(Vec2D, Vec2D)> = spans
+            .into_iter()
+            .filter(|(start, end)| end - start >= MIN_HATCH_LENGTH)
+            .map(|(start, end)| {
+                (
+                    turn(Vec2D { x: start, y }, sin, cos),
+                    turn(Vec2D { x: end, y }, sin, cos),
+                )
+            })
+            .collect();
+        if row % 2 != 0 {
+            row_lines.reverse();
+            for line in row_lines.iter_mut() {
+                *line = (line.1, line.0);
+            }
+        }
+        hatched.extend(row_lines);
+    }
+
+    Ok(hatched)
+}
+
+/// Fills absolute polygons with hatch lines, as pen-up travel to each line and a
+/// pen-down move along it
+pub fn hatch(shapes: &[Vec<Vec2D>], settings: &Hatch) -> Result<Vec<Movement>, HatchError> {
+    if !(settings.spacing.is_finite() && settings.spacing > 0.0) {
+        return Err(HatchError::BadSpacing(settings.spacing));
+    }
+
+    let mut angles = vec![settings.angle];
+    if settings.cross {
+        angles.push(settings.angle + 90.0);
+    }
+
+    let mut movements: Vec<Movement> = Vec::new();
+    for angle in angles {
+        for (start, end) in lines(shapes, angle, settings.spacing, settings.inset)? {
+            movements.push(Movement {
+                dest: start,
+                pen_down: false,
+                ..Default::default()
+            });
+            movements.push(Movement {
+                dest: end,
+                pen_down: true,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(movements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(size: f32) -> Vec<Vec2D> {
+        vec![
+            Vec2D { x: 0.0, y: 0.0 },
+            Vec2D { x: size, y: 0.0 },
+            Vec2D { x: size, y: size },
+            Vec2D { x: 0.0, y: size },
+        ]
+    }
+
+    fn spaced(spacing: f32) -> Hatch {
+        Hatch {
+            spacing,
+            angle: 0.0,
+            cross: false,
+            inset: 0.0,
+        }
+    }
+
+    #[test]
+    fn one_line_per_row() {
+        let movements = hatch(&[square(10.0)], &spaced(1.0)).unwrap();
+        assert_eq!(movements.iter().filter(|mv| mv.pen_down).count(), 10);
+    }
+
+    #[test]
+    fn shapes_sent_with_their_outline_are_filled() {
+        let shape = square(10.0);
+        let mut outline: Vec<Movement> = shape
+            .iter()
+            .chain(shape.first())
+            .map(|point| Movement {
+                dest: *point,
+                pen_down: true,
+                ..Default::default()
+            })
+            .collect();
+        outline[0].pen_down = false;
+
+        let shapes = fill_shapes(vec![shape], &outline);
+        assert_eq!(shapes.len(), 1);
+        let movements = hatch(&shapes, &spaced(1.0)).unwrap();
+        assert_eq!(movements.iter().filter(|mv| mv.pen_down).count(), 10);
+
+        // without shapes, the outline is what gets filled
+        assert_eq!(fill_shapes(Vec::new(), &outline).len(), 1);
+    }
+
+    #[test]
+    fn tiny_spacing_is_refused() {
+        assert!(matches!(
+            hatch(&[square(100.0)], &spaced(1e-7)),
+            Err(HatchError::TooManyRows(rows)) if rows > MAX_HATCH_ROWS
+        ));
+        assert_eq!(
+            hatch(&[square(100.0)], &spaced(0.0)).err(),
+            Some(HatchError::BadSpacing(0.0))
+        );
+    }
+}
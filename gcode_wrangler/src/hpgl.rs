@@ -0,0 +1,209 @@
+use std::fmt;
+
+use crate::models::{Movement, PenProfile, Vec2D};
+
+/// HP-GL plotter units in a millimetre
+pub const PLOTTER_UNITS_PER_MM: f32 = 40.0;
+
+// ends a label unless DT has changed it
+const LABEL_TERMINATOR: char = '\u{3}';
+
+#[derive(Debug, PartialEq)]
+pub enum HpglError {
+    // SP asked for a pen past the end of the machine's list
+    UnknownPen(u32),
+    BadNumber { instruction: String, text: String },
+}
+
+impl fmt::Display for HpglError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HpglError::UnknownPen(number) => {
+                write!(f, "SP{number}: the machine has no pen {number}")
+            }
+            HpglError::BadNumber { instruction, text } => {
+                write!(f, "{instruction}: {text:?} is not a number")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HpglError {}
+
+/// A plot read from HP-GL
+#[derive(Clone, Debug, Default)]
+pub struct Hpgl {
+    // absolute, in millimetres
+    pub movements: Vec<Movement>,
+    // instructions that were understood but not drawn, like labels, and ones that
+    // weren't understood at all
+    pub skipped: Vec<String>,
+}
+
+// One instruction: its mnemonic, in capitals, and what came after it
+fn instructions(source: &str) -> Vec<(String, String)> {
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if !c.is_ascii_alphabetic() {
+            continue;
+        }
+        let mnemonic: String = match chars.next_if(char::is_ascii_alphabetic) {
+            Some(second) => [c, second].iter().collect::<String>().to_ascii_uppercase(),
+            None => continue,
+        };
+        // a label can have anything in it, up to its terminator
+        let parameters: String = if mnemonic == "LB" {
+            chars
+                .by_ref()
+                .take_while(|c| *c != LABEL_TERMINATOR)
+                .collect()
+        } else {
+            let mut parameters = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_ascii_alphabetic() && *c != ';') {
+                parameters.push(c);
+            }
+            parameters
+        };
+        parsed.push((mnemonic, parameters));
+    }
+
+    parsed
+}
+
+fn numbers(mnemonic: &str, parameters: &str) -> Result<Vec<f32>, HpglError> {
+    parameters
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|text| !text.is_empty())
+        .map(|text| {
+            text.parse().map_err(|_| HpglError::BadNumber {
+                instruction: mnemonic.to_string(),
+                text: text.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Reads HP-GL into movements scaled from plotter units to millimetres. `SP` picks the
+/// numbered pen from `pens`, counting from 1, and `SP0` just lifts the pen. Labels are
+/// skipped, since there's no font to draw them with.
+pub fn import(source: &str, pens: &[PenProfile]) -> Result<Hpgl, HpglError> {
+    let mut plot = Hpgl::default();
+    let mut position = Vec2D::default();
+    let mut pen_down = false;
+    let mut relative = false;
+    let mut pen: Option<String> = None;
+
+    for (mnemonic, parameters) in instructions(source) {
+        match mnemonic.as_str() {
+            "IN" => {
+                pen_down = false;
+                relative = false;
+            }
+            "SP" => {
+                pen_down = false;
+                let number = numbers(&mnemonic, &parameters)?
+                    .first()
+                    .map_or(0, |number| *number as u32);
+                if number > 0 {
+                    let profile = pens
+                        .get(number as usize - 1)
+                        .ok_or(HpglError::UnknownPen(number))?;
+                    pen = Some(profile.name.clone());
+                }
+            }
+            "PU" | "PD" | "PA" | "PR" => {
+                match mnemonic.as_str() {
+                    "PU" => pen_down = false,
+                    "PD" => pen_down = true,
+                    "PA" => relative = false,
+                    _ => relative = true,
+                }
+                // an odd number out at the end has nothing to pair with
+                for pair in numbers(&mnemonic, &parameters)?.chunks_exact(2) {
+                    let point = Vec2D {
+                        x: pair[0] / PLOTTER_UNITS_PER_MM,
+                        y: pair[1] / PLOTTER_UNITS_PER_MM,
+                    };
+                    position = if relative { position + point } else { point };
+                    plot.movements.push(Movement {
+                        dest: position,
+                        pen_down,
+                        arc: None,
+                        pen: pen.clone(),
+                    });
+                }
+            }
+            _ => plot.skipped.push(mnemonic),
+        }
+    }
+
+    Ok(plot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Actuator;
+
+    fn pen(name: &str) -> PenProfile {
+        PenProfile {
+            name: name.to_string(),
+            actuator: Actuator::Spindle,
+            up: 0.0,
+            down: 254.0,
+            settle_ms: 0,
+            lift_speed: None,
+            color: None,
+        }
+    }
+
+    // (x, y, pen down) for each movement
+    fn points(plot: &Hpgl) -> Vec<(f32, f32, bool)> {
+        plot.movements
+            .iter()
+            .map(|mv| (mv.dest.x, mv.dest.y, mv.pen_down))
+            .collect()
+    }
+
+    #[test]
+    fn pen_up_down_absolute_and_relative() {
+        let plot = import("IN;PU400,400;PD800,400,800,800;PR-400,0;PU;PA0,0;", &[]).unwrap();
+        assert_eq!(
+            points(&plot),
+            [
+                (10.0, 10.0, false),
+                (20.0, 10.0, true),
+                (20.0, 20.0, true),
+                // PR leaves the pen down, and relative to where it was
+                (10.0, 20.0, true),
+                (0.0, 0.0, false),
+            ]
+        );
+        assert!(plot.skipped.is_empty());
+    }
+
+    #[test]
+    fn sp_picks_pens_counting_from_one() {
+        let pens = [pen("black"), pen("red")];
+        let plot = import("SP2;PD40,0;SP0;PA80,0;SP1;LBhello\u{3};PD120,0;", &pens).unwrap();
+        let used: Vec<(Option<&str>, bool)> = plot
+            .movements
+            .iter()
+            .map(|mv| (mv.pen.as_deref(), mv.pen_down))
+            .collect();
+        // SP0 lifts the pen but leaves the last one selected
+        assert_eq!(
+            used,
+            [
+                (Some("red"), true),
+                (Some("red"), false),
+                (Some("black"), true),
+            ]
+        );
+        assert_eq!(plot.skipped, ["LB"]);
+
+        assert_eq!(import("SP3;", &pens).err(), Some(HpglError::UnknownPen(3)));
+    }
+}
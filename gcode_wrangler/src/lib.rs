@@ -1,6 +1,14 @@
-use models::{MachineDetails, Movement, Vec2D};
+use dialect::Dialect;
+use emitter::Emitter;
+use masks::Masks;
+use models::{
+    Actuator, ArcDescription, Feedrates, JobInfo, Layer, MachineDetails, Movement, PenProfile,
+    Vec2D,
+};
 use serde::Serialize;
+use std::fmt;
 use std::ops::{Add, Sub};
+use std::sync::Arc;
 use std::{thread, time};
 
 use tokio::sync::mpsc::{Receiver as SingleReceiver, Sender as MultiSender};
@@ -9,77 +17,103 @@ use tokio::sync::{mpsc, watch};
 
 use serialport::{Error, SerialPort};
 
+pub mod arcs;
+pub mod dialect;
+pub mod dxf;
+pub mod emitter;
+pub mod fill;
+pub mod hpgl;
+pub mod masks;
 pub mod models;
+pub mod optimize;
+pub mod parser;
+pub mod sanitize;
+pub mod summary;
+pub mod svg;
+pub mod transform;
 
-#[derive(Serialize, Clone)]
-pub enum Flavor {
-    GRBL,
-    Marlin,
-}
-
-impl Flavor {
-    fn render(&self, operations: &[GCode]) -> Vec<String> {
-        operations.iter().map(|op| op.render(self)).collect()
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Position {
     Absolute,
     Relative,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Units {
     Inches,
     Millimeters,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StepperState {
     Enabled,
     Disabled,
 }
 
-// pub struct SimulationState {
-//     real_position: Vec3,
-//     virtual_position: Vec3,
-//     max_travel: Vec3,
-//     min_travel: Vec3,
-//     positioning: Position,
-//     feedrate: u32,
-//     active: bool,
-// }
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Vec3 {
-    x: Option<f32>,
-    y: Option<f32>,
-    z: Option<f32>,
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub z: Option<f32>,
 }
 
 impl Vec3 {
-    fn to_string(self) -> Result<String, &'static str> {
-        // match (self.x, self.y, self.z) {
-        //     (None, None, None) => Err("At least one dimension must be provided"),
-        //     (_, _, _) => {
+    fn is_empty(&self) -> bool {
+        self.x.is_none() && self.y.is_none() && self.z.is_none()
+    }
+
+    fn to_string(self) -> Result<String, RenderError> {
         let mut parts: Vec<String> = Vec::new();
-        if let Some(x) = self.x {
-            parts.push(format!("X{}", x))
-        }
-        if let Some(y) = self.y {
-            parts.push(format!("Y{}", y))
-        }
-        if let Some(z) = self.z {
-            parts.push(format!("Z{}", z))
+        for (axis, value) in [('X', self.x), ('Y', self.y), ('Z', self.z)] {
+            if let Some(value) = value {
+                if !value.is_finite() {
+                    return Err(RenderError::NonFinite { axis, value });
+                }
+                // adding zero turns -0 into 0
+                parts.push(format!("{}{}", axis, value + 0.0))
+            }
         }
         Ok(parts.join(" "))
-        //     }
-        // }
     }
 }
 
-#[derive(Debug)]
+/// The two ways G2/G3 can say where the circle is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArcCenter {
+    // I and J, the center's offset from the start of the arc
+    Offset { i: f32, j: f32 },
+    // R; negative for the long way round
+    Radius(f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderError {
+    Unsupported { instruction: GCode, dialect: String },
+    EmptyTarget(GCode),
+    NonFinite { axis: char, value: f32 },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Unsupported {
+                instruction,
+                dialect,
+            } => write!(f, "{instruction:?} is not supported by {dialect}"),
+            RenderError::EmptyTarget(instruction) => {
+                write!(f, "{instruction:?} needs at least one axis")
+            }
+            RenderError::NonFinite { axis, value } => {
+                write!(f, "{axis} coordinate is not a finite number ({value})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum GCode {
     Activate,
     Deactivate,
@@ -100,6 +134,18 @@ pub enum GCode {
         feedrate: Option<u32>,
     },
 
+    ClockwiseArc {
+        target: Vec3,
+        center: ArcCenter,
+        feedrate: Option<u32>,
+    },
+
+    CounterClockwiseArc {
+        target: Vec3,
+        center: ArcCenter,
+        feedrate: Option<u32>,
+    },
+
     Home {
         x: bool,
         y: bool,
@@ -111,165 +157,19 @@ pub enum GCode {
         y: StepperState,
         z: StepperState,
     },
-}
 
-impl GCode {
-    fn render(&self, flavor: &Flavor) -> String {
-        match self {
-            GCode::Activate => match flavor {
-                Flavor::GRBL => "M3 S254\nG4 P0.3".to_string(),
-                Flavor::Marlin => GCode::LinearMove {
-                    target: Vec3 {
-                        x: Some(0.0),
-                        y: Some(0.0),
-                        z: Some(-5.0),
-                    },
-                    feedrate: None,
-                }
-                .render(flavor),
-            },
-            GCode::Deactivate => match flavor {
-                Flavor::GRBL => "M3 S65\nG4 P0.3".to_string(),
-                Flavor::Marlin => GCode::LinearMove {
-                    target: Vec3 {
-                        x: Some(0.0),
-                        y: Some(0.0),
-                        z: Some(5.0),
-                    },
-                    feedrate: None,
-                }
-                .render(flavor),
-            },
-            GCode::EndProgram => match flavor {
-                Flavor::GRBL => "M5\nM2".to_string(),
-                Flavor::Marlin => "M5".to_string(),
-            },
-            GCode::Home { x, y, z } => match flavor {
-                Flavor::GRBL => "$H".to_string(),
-                Flavor::Marlin => {
-                    let mut parts: Vec<&str> = vec!["G28"];
-                    if *x {
-                        parts.push("X")
-                    };
-                    if *y {
-                        parts.push("Y")
-                    };
-                    if *z {
-                        parts.push("Z")
-                    };
-
-                    parts.join(" ")
-                }
-            },
-            GCode::StepperControl { x, y, z } => match flavor {
-                Flavor::GRBL => unimplemented!(),
-                Flavor::Marlin => {
-                    let mut enable: Vec<&str> = vec!["M17"];
-                    let mut disable: Vec<&str> = vec!["M18"];
-
-                    match x {
-                        crate::StepperState::Enabled => enable.push("X"),
-                        crate::StepperState::Disabled => disable.push("X"),
-                    }
-                    match y {
-                        crate::StepperState::Enabled => enable.push("Y"),
-                        crate::StepperState::Disabled => disable.push("Y"),
-                    }
-                    match z {
-                        crate::StepperState::Enabled => enable.push("Z"),
-                        crate::StepperState::Disabled => disable.push("Z"),
-                    }
-
-                    let mut lines: Vec<String> = Vec::new();
-
-                    if disable.len() > 1 {
-                        lines.push(disable.join(" "))
-                    }
-                    if enable.len() > 1 {
-                        lines.push(enable.join(" "))
-                    }
+    Comment(String),
 
-                    lines.join("\n")
-                }
-            },
-            GCode::SetXY => "G17".to_string(),
-            GCode::Pause(ms) => {
-                format!("G4 P{}", (*ms as f32) / 1000.0)
-            }
-            GCode::SetPositionMode(pmode) => match pmode {
-                Position::Absolute => "G90",
-                Position::Relative => "G91",
-            }
-            .to_string(),
-            GCode::SetCurrentPosition(current) => {
-                format!("G92 {}", current.to_string().unwrap())
-            }
-            GCode::SetUnits(units) => match units {
-                crate::Units::Inches => "G20",
-                crate::Units::Millimeters => "G21",
-            }
-            .to_string(),
-            GCode::LinearMove { target, feedrate } => {
-                let mut parts: Vec<String> = Vec::new();
-                parts.push("G0".to_string());
-                if let Some(feedrate) = feedrate {
-                    parts.push(format!("F{}", feedrate))
-                }
-                parts.push(target.to_string().unwrap());
-                parts.join(" ")
-            }
-            GCode::LinearDraw { target, feedrate } => {
-                let mut parts: Vec<String> = Vec::new();
-                parts.push("G1".to_string());
-                if let Some(feedrate) = feedrate {
-                    parts.push(format!("F{}", feedrate))
-                }
-                parts.push(target.to_string().unwrap());
-                parts.join(" ")
-            }
-        }
-    }
+    // Park and wait for the operator to load the named pen
+    ToolChange(String),
 
-    pub fn preamble(flavor: &Flavor) -> Vec<GCode> {
-        match flavor {
-            Flavor::GRBL => vec![
-                GCode::SetUnits(Units::Millimeters),
-                GCode::SetPositionMode(Position::Absolute),
-                GCode::Deactivate,
-                GCode::LinearMove {
-                    target: Vec3 {
-                        x: None,
-                        y: None,
-                        z: None,
-                    },
-                    feedrate: Some(1000),
-                },
-                GCode::SetCurrentPosition(Vec3 {
-                    x: Some(0.0),
-                    y: Some(0.0),
-                    z: Some(0.0),
-                }),
-            ],
-            Flavor::Marlin => todo!(),
-        }
-    }
+    // A line we couldn't interpret, passed through verbatim
+    Raw(String),
+}
 
-    pub fn footer(flavor: &Flavor) -> Vec<GCode> {
-        match flavor {
-            Flavor::GRBL => vec![
-                GCode::Deactivate,
-                GCode::LinearMove {
-                    target: Vec3 {
-                        x: Some(0.0),
-                        y: Some(0.0),
-                        z: None,
-                    },
-                    feedrate: None,
-                },
-                GCode::EndProgram,
-            ],
-            Flavor::Marlin => todo!(),
-        }
+impl GCode {
+    pub fn render(&self, dialect: &dyn Dialect, pen: &PenProfile) -> Result<String, RenderError> {
+        dialect.render(self, pen)
     }
 }
 
@@ -307,11 +207,54 @@ impl Add<Vec3> for Vec3 {
     }
 }
 
-pub fn to_gcode(movements: &Vec<Movement>, position_mode: Position) -> Vec<GCode> {
+pub fn to_gcode(
+    movements: &Vec<Movement>,
+    position_mode: Position,
+    feedrates: &Feedrates,
+    arc_tolerance: Option<f32>,
+) -> Vec<GCode> {
     // accepts either position mode, but always produces absolute coordinates based on
     // starting at (0, 0).
 
+    // arc fitting needs to see the shape, so work out where everything actually is first
+    let fitted: Vec<Movement>;
+    let (movements, position_mode) = match arc_tolerance {
+        Some(tolerance) => {
+            let mut position = Vec2D::default();
+            let absolute: Vec<Movement> = movements
+                .iter()
+                .map(|mv| {
+                    let start = position;
+                    if position_mode == Position::Relative {
+                        position = position + mv.dest;
+                    } else {
+                        position = mv.dest;
+                    }
+                    Movement {
+                        dest: position,
+                        pen_down: mv.pen_down,
+                        pen: mv.pen.clone(),
+                        arc: mv.arc.map(|arc| match position_mode {
+                            Position::Absolute => arc,
+                            Position::Relative => ArcDescription {
+                                center: start + arc.center,
+                                ..arc
+                            },
+                        }),
+                    }
+                })
+                .collect();
+            fitted = arcs::fit(&absolute, Vec2D::default(), tolerance);
+            (&fitted, Position::Absolute)
+        }
+        None => (movements, position_mode),
+    };
+
     let mut active: bool = false;
+    // whatever pen the first movement names is assumed to be loaded already
+    let mut loaded: Option<&str> = None;
+    // F is modal and shared by every motion command, so only say it when it changes
+    let mut current_feedrate: Option<u32> = None;
     let mut position = Vec3 {
         x: Some(0.0),
         y: Some(0.0),
@@ -326,38 +269,153 @@ pub fn to_gcode(movements: &Vec<Movement>, position_mode: Position) -> Vec<GCode
             Position::Relative => position + Vec3::from(mv.dest),
         };
 
-        match (active, mv.pen_down) {
-            (true, true) => {
-                as_gcode.push(GCode::LinearDraw {
-                    target: dest,
-                    feedrate: None,
+        if let Some(pen) = mv.pen.as_deref() {
+            if loaded.is_some_and(|loaded| loaded != pen) {
+                if active {
+                    as_gcode.push(GCode::Deactivate);
+                    active = false;
+                }
+                as_gcode.push(GCode::ToolChange(pen.to_string()));
+                // parking moved the machine, so head back to where the last pen stopped
+                let target = position;
+                let feedrate = Some(feedrates.travel);
+                as_gcode.push(if feedrates.rapid_travel {
+                    GCode::LinearMove { target, feedrate }
+                } else {
+                    GCode::LinearDraw { target, feedrate }
                 });
+                current_feedrate = feedrate;
             }
-            (true, false) => {
-                as_gcode.push(GCode::Deactivate);
-                as_gcode.push(GCode::LinearMove {
+            loaded = Some(pen);
+        }
+
+        if active != mv.pen_down {
+            as_gcode.push(if mv.pen_down {
+                GCode::Activate
+            } else {
+                GCode::Deactivate
+            });
+            active = mv.pen_down;
+            // a pen on the Z axis may have set its own lift speed
+            current_feedrate = None;
+        }
+
+        let speed = if mv.pen_down {
+            feedrates.draw
+        } else {
+            feedrates.travel
+        };
+        let feedrate = if current_feedrate == Some(speed) {
+            None
+        } else {
+            Some(speed)
+        };
+        current_feedrate = Some(speed);
+
+        // nothing to see with the pen up, so travel arcs take the short way
+        let arc = mv.arc.filter(|_| mv.pen_down);
+
+        if let Some(ArcDescription { clockwise, center }) = arc {
+            let start = Vec2D {
+                x: position.x.unwrap_or(0.0),
+                y: position.y.unwrap_or(0.0),
+            };
+            let offset = match position_mode {
+                Position::Absolute => center - start,
+                Position::Relative => center,
+            };
+            let center = ArcCenter::Offset {
+                i: offset.x,
+                j: offset.y,
+            };
+            as_gcode.push(if clockwise {
+                GCode::ClockwiseArc {
                     target: dest,
-                    feedrate: None,
-                });
-                active = mv.pen_down;
-            }
-            (false, true) => {
-                as_gcode.push(GCode::Activate);
-                as_gcode.push(GCode::LinearDraw {
+                    center,
+                    feedrate,
+                }
+            } else {
+                GCode::CounterClockwiseArc {
                     target: dest,
-                    feedrate: None,
-                });
-                active = mv.pen_down;
+                    center,
+                    feedrate,
+                }
+            });
+        } else if mv.pen_down || !feedrates.rapid_travel {
+            // with the pen up, a G1 is just a gentler travel move
+            as_gcode.push(GCode::LinearDraw {
+                target: dest,
+                feedrate,
+            });
+        } else {
+            as_gcode.push(GCode::LinearMove {
+                target: dest,
+                feedrate,
+            });
+        }
+
+        position = dest;
+    }
+
+    as_gcode
+}
+
+/// Runs each layer at its own speeds, as many times as it asks, stopping for a pen
+/// change whenever the next layer wants a different pen. Layers are absolute and the
+/// first layer's pen is assumed to be loaded already. A layer without a pen keeps
+/// whichever one is loaded. Repeats start from wherever the layer first started.
+pub fn layers_to_gcode(
+    layers: &[Layer],
+    feedrates: &Feedrates,
+    arc_tolerance: Option<f32>,
+) -> Vec<GCode> {
+    let mut as_gcode: Vec<GCode> = Vec::new();
+    let mut loaded: Option<&str> = None;
+    let mut position = Vec2D::default();
+
+    for layer in layers {
+        let start = position;
+        let pen = layer.pen.as_deref().or(loaded);
+        let layer_feedrates = feedrates.with_overrides(layer.draw_speed, layer.travel_speed, None);
+        let gcode = to_gcode(
+            &layer.movements,
+            Position::Absolute,
+            &layer_feedrates,
+            arc_tolerance,
+        );
+
+        for pass in 0..layer.repeat {
+            // each pass starts from the top with the pen up
+            let pen_down = as_gcode
+                .iter()
+                .rev()
+                .find(|op| matches!(op, GCode::Activate | GCode::Deactivate))
+                == Some(&GCode::Activate);
+            if pen_down {
+                as_gcode.push(GCode::Deactivate);
             }
-            (false, false) => {
-                as_gcode.push(GCode::LinearMove {
-                    target: dest,
-                    feedrate: None,
+            if let Some(pen) = pen {
+                if loaded.is_some_and(|loaded| loaded != pen) {
+                    as_gcode.push(GCode::ToolChange(pen.to_string()));
+                }
+                loaded = Some(pen);
+            }
+            // a layer that starts drawing straight away would otherwise draw from
+            // wherever the last pass finished
+            if pass > 0 && layer.movements.first().is_some_and(|mv| mv.pen_down) {
+                let target = Vec3::from(start);
+                let feedrate = Some(feedrates.travel);
+                as_gcode.push(if feedrates.rapid_travel {
+                    GCode::LinearMove { target, feedrate }
+                } else {
+                    GCode::LinearDraw { target, feedrate }
                 });
             }
+            as_gcode.extend(gcode.iter().cloned());
+        }
+        if let Some(last) = layer.movements.last() {
+            position = last.dest;
         }
-
-        position = dest;
     }
 
     as_gcode
@@ -371,32 +429,70 @@ pub enum PortCmd {
     PAUSE,
     STOP,
     CANCEL,
+    PENCHANGE,
+}
+
+/// What the serial thread is up to, as reported over the API
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelStatus {
+    Idle,
+    Running,
+    Paused,
+    WaitingForPenChange,
+    Stopped,
+    Cancelled,
 }
 
+impl From<&PortCmd> for ChannelStatus {
+    fn from(cmd: &PortCmd) -> Self {
+        match cmd {
+            PortCmd::WAIT => ChannelStatus::Idle,
+            PortCmd::SEND(_) | PortCmd::RUN => ChannelStatus::Running,
+            PortCmd::PAUSE => ChannelStatus::Paused,
+            PortCmd::STOP => ChannelStatus::Stopped,
+            PortCmd::CANCEL => ChannelStatus::Cancelled,
+            PortCmd::PENCHANGE => ChannelStatus::WaitingForPenChange,
+        }
+    }
+}
+
+/// Progress, status, the command sender, and the channel itself to run on its own thread
+pub type ChannelHandles = (
+    Receiver<usize>,
+    Receiver<ChannelStatus>,
+    MultiSender<PortCmd>,
+    SerialChannel,
+);
+
 pub struct SerialChannel {
     progress: Sender<usize>,
+    state: Sender<ChannelStatus>,
     command: SingleReceiver<PortCmd>,
     status: PortCmd,
     port: Box<dyn SerialPort>,
+    flavor: Arc<dyn Dialect>,
     buffer: Vec<String>,
 }
 
 impl SerialChannel {
-    pub fn new(
-        machine: &MachineDetails,
-    ) -> Result<(Receiver<usize>, MultiSender<PortCmd>, Self), Error> {
+    pub fn new(machine: &MachineDetails) -> Result<ChannelHandles, Error> {
         match serialport::new(machine.port.clone(), machine.baud_rate).open() {
             Ok(port) => {
                 let (cmd_tx, cmd_rx) = mpsc::channel(64);
                 let (progress_tx, progress_rx) = watch::channel(0usize);
+                let (state_tx, state_rx) = watch::channel(ChannelStatus::Idle);
                 Ok((
                     progress_rx,
+                    state_rx,
                     cmd_tx,
                     SerialChannel {
                         progress: progress_tx,
+                        state: state_tx,
                         command: cmd_rx,
                         status: PortCmd::WAIT,
                         port,
+                        flavor: machine.flavor.clone(),
                         buffer: Vec::new(),
                     },
                 ))
@@ -431,9 +527,21 @@ impl SerialChannel {
                         self.status = PortCmd::STOP;
                         break;
                     }
-                    PortCmd::RUN => self.status = PortCmd::RUN,
+                    PortCmd::RUN => {
+                        if let PortCmd::PENCHANGE = self.status {
+                            let resume = self.flavor.resume_tool_change();
+                            if let Err(e) = self.port.write_all(resume.as_bytes()) {
+                                println!("Failed to resume after pen change: {:?}", e);
+                                continue;
+                            }
+                        }
+                        self.status = PortCmd::RUN
+                    }
+                    PortCmd::PENCHANGE => self.status = PortCmd::PENCHANGE,
                     PortCmd::CANCEL => {
-                        self.port.write_all(b"!").expect("Failed to send halt command");
+                        self.port
+                            .write_all(b"!")
+                            .expect("Failed to send halt command");
                         self.status = PortCmd::CANCEL;
                     }
                 },
@@ -448,12 +556,19 @@ impl SerialChannel {
                 PortCmd::PAUSE => (),
                 PortCmd::WAIT => (),
                 PortCmd::CANCEL => (),
+                PortCmd::PENCHANGE => (),
                 PortCmd::RUN => match self.buffer.pop() {
                     Some(next_cmd) => {
                         println!("> {}", next_cmd);
                         if let Err(e) = self.port.write_all(format!("{}\n", next_cmd).as_bytes()) {
                             println!("Failed to write to serial port: {:?}", e);
                             self.status = PortCmd::PAUSE
+                        } else if self.flavor.is_tool_change(&next_cmd) {
+                            // the controller won't answer until the operator is done
+                            self.status = PortCmd::PENCHANGE;
+                            self.progress
+                                .send(self.buffer.len())
+                                .expect("Progress channel closed unexpectedly")
                         } else {
                             for attempt in 0..600 {
                                 match self.port.bytes_to_read() {
@@ -485,20 +600,186 @@ impl SerialChannel {
                 },
                 PortCmd::SEND(_) => (),
             }
+            self.state.send_replace((&self.status).into());
             thread::sleep(thread_delay);
         }
+        self.state.send_replace(ChannelStatus::Stopped);
         println!("Serial monitor exiting");
     }
 }
 
-pub fn to_program(gcode: &[GCode], flavor: Flavor) -> Vec<String> {
-    let mut rendered: Vec<String> = vec![];
+/// Renders a whole program for the dialect, starting with `pen` and switching to any
+/// of `pens` a tool change names, in `units`. With `job`, it starts with a comment header saying
+/// what the program is, for anyone reading it later.
+pub fn to_program(
+    gcode: &[GCode],
+    dialect: &dyn Dialect,
+    pen: &PenProfile,
+    pens: &[PenProfile],
+    precision: usize,
+    units: Units,
+    job: Option<&JobInfo>,
+) -> Result<Vec<String>, RenderError> {
+    // the pens' heights are lengths too when they're on the Z axis
+    let loaded = pen_in_units(pen, units, precision);
+    let converted: Vec<PenProfile> = pens
+        .iter()
+        .map(|pen| pen_in_units(pen, units, precision))
+        .collect();
+    let mut emitter = Emitter::new(dialect, &loaded, precision).with_pens(&converted);
 
-    rendered.extend(flavor.render(&GCode::preamble(&flavor)));
-    rendered.extend(flavor.render(gcode));
-    rendered.extend(flavor.render(&GCode::footer(&flavor)));
+    let preamble = dialect.preamble();
+    let footer = dialect.footer();
+    emitter.emit_all(&in_units(&preamble, units))?;
+    emitter.emit_all(&in_units(gcode, units))?;
+    emitter.emit_all(&in_units(&footer, units))?;
+    let body = emitter.finish();
 
-    rendered
+    let job = match job {
+        Some(job) => job,
+        None => return Ok(body),
+    };
+
+    let everything: Vec<GCode> = [preamble, gcode.to_vec(), footer].concat();
+    let summary = summary::summarize(&everything, pen);
+    let line_count: usize = body.iter().map(|line| line.lines().count()).sum();
+    let seconds = summary.duration.as_secs();
+
+    let mut header: Vec<String> = Vec::new();
+    for (key, value) in [
+        ("title", &job.title),
+        ("user", &job.user),
+        ("handle", &job.handle),
+        ("pen", &job.pen),
+    ] {
+        if let Some(value) = value {
+            header.push(format!("{key}: {value}"));
+        }
+    }
+    // the summary's in millimetres, and the header should agree with the body
+    let (scale, unit) = match units {
+        Units::Millimeters => (1.0, "mm"),
+        Units::Inches => (1.0 / 25.4, "in"),
+    };
+    if let Some((low, high)) = summary.bounds {
+        header.push(format!(
+            "bounds: X{:.p$} Y{:.p$} to X{:.p$} Y{:.p$} {unit}",
+            low.x * scale,
+            low.y * scale,
+            high.x * scale,
+            high.y * scale,
+            p = precision
+        ));
+    }
+    header.push(format!("lines: {line_count}"));
+    header.push(format!(
+        "estimated duration: {}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    ));
+
+    let mut program = header
+        .into_iter()
+        .map(|text| dialect.render(&GCode::Comment(text), pen))
+        .collect::<Result<Vec<String>, RenderError>>()?;
+    program.extend(body);
+    Ok(program)
+}
+
+/// Converts a program in millimetres to `units`, including any G20/G21 in it. Pen
+/// heights come from the pen profiles, so they're converted by `pen_in_units`.
+pub fn in_units(gcode: &[GCode], units: Units) -> Vec<GCode> {
+    let factor = match units {
+        Units::Millimeters => return gcode.to_vec(),
+        Units::Inches => 1.0 / 25.4,
+    };
+    gcode
+        .iter()
+        .map(|op| match op {
+            GCode::SetUnits(_) => GCode::SetUnits(units),
+            other => scaled(other, factor),
+        })
+        .collect()
+}
+
+/// A pen profile, configured in millimetres, for a program in `units` with `precision`
+/// decimal places. Only a Z axis pen's heights and lift speed are lengths; servo values
+/// and commands are left alone.
+pub fn pen_in_units(pen: &PenProfile, units: Units, precision: usize) -> PenProfile {
+    let scale = 10f32.powi(precision as i32);
+    let height = |value: f32| (value / 25.4 * scale).round() / scale + 0.0;
+    match (units, &pen.actuator) {
+        (Units::Inches, Actuator::ZAxis) => PenProfile {
+            up: height(pen.up),
+            down: height(pen.down),
+            lift_speed: pen
+                .lift_speed
+                .map(|speed| ((speed as f32 / 25.4).round() as u32).max(1)),
+            ..pen.clone()
+        },
+        _ => pen.clone(),
+    }
+}
+
+/// Multiplies every length and speed in an instruction by `factor`
+pub fn scaled(op: &GCode, factor: f32) -> GCode {
+    let length = |value: Option<f32>| value.map(|value| value * factor);
+    let point = |target: Vec3| Vec3 {
+        x: length(target.x),
+        y: length(target.y),
+        z: length(target.z),
+    };
+    let speed = |feedrate: Option<u32>| {
+        feedrate.map(|feedrate| ((feedrate as f32 * factor).round() as u32).max(1))
+    };
+    let center = |center: ArcCenter| match center {
+        ArcCenter::Offset { i, j } => ArcCenter::Offset {
+            i: i * factor,
+            j: j * factor,
+        },
+        ArcCenter::Radius(radius) => ArcCenter::Radius(radius * factor),
+    };
+
+    match op {
+        GCode::SetCurrentPosition(position) => GCode::SetCurrentPosition(point(*position)),
+        GCode::LinearMove { target, feedrate } => GCode::LinearMove {
+            target: point(*target),
+            feedrate: speed(*feedrate),
+        },
+        GCode::LinearDraw { target, feedrate } => GCode::LinearDraw {
+            target: point(*target),
+            feedrate: speed(*feedrate),
+        },
+        GCode::ClockwiseArc {
+            target,
+            center: arc,
+            feedrate,
+        } => GCode::ClockwiseArc {
+            target: point(*target),
+            center: center(*arc),
+            feedrate: speed(*feedrate),
+        },
+        GCode::CounterClockwiseArc {
+            target,
+            center: arc,
+            feedrate,
+        } => GCode::CounterClockwiseArc {
+            target: point(*target),
+            center: center(*arc),
+            feedrate: speed(*feedrate),
+        },
+        other => other.clone(),
+    }
+}
+
+/// For controllers that can't cope with comments
+pub fn without_comments(gcode: &[GCode]) -> Vec<GCode> {
+    gcode
+        .iter()
+        .filter(|op| !matches!(op, GCode::Comment(_)))
+        .cloned()
+        .collect()
 }
 
 impl Add<Vec2D> for Vec2D {
@@ -523,39 +804,379 @@ impl Sub<Vec2D> for Vec2D {
     }
 }
 
+/// How much of a job fell off the bed
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ClipReport {
+    // pen-down segments that were cut short or dropped entirely
+    pub segments_clipped: usize,
+    // how much drawing, in mm, was lost
+    pub distance_clipped: f32,
+}
+
+// Adds an absolute movement, converting it if the output is relative
+fn push_movement(movements: &mut Vec<Movement>, machine: &mut Vec2D, mode: Position, mv: Movement) {
+    let dest = mv.dest;
+    movements.push(match mode {
+        Position::Absolute => mv,
+        Position::Relative => Movement {
+            dest: dest - *machine,
+            arc: mv.arc.map(|arc| ArcDescription {
+                center: arc.center - *machine,
+                ..arc
+            }),
+            ..mv
+        },
+    });
+    *machine = dest;
+}
+
+// Liang-Barsky: the fractions along `from`-`to` where it enters and leaves the bed, or
+// None if it never touches it
+fn clip_segment(from: Vec2D, to: Vec2D, dimensions: Vec2D) -> Option<(f32, f32)> {
+    let delta = to - from;
+    let mut enter: f32 = 0.0;
+    let mut leave: f32 = 1.0;
+
+    for (p, q) in [
+        (-delta.x, from.x),
+        (delta.x, dimensions.x - from.x),
+        (-delta.y, from.y),
+        (delta.y, dimensions.y - from.y),
+    ] {
+        if p == 0.0 {
+            // parallel to this edge, so either always inside it or never
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                enter = enter.max(t);
+            } else {
+                leave = leave.min(t);
+            }
+        }
+    }
+
+    if enter > leave {
+        None
+    } else {
+        Some((enter, leave))
+    }
+}
+
+/// Keeps a job on the bed and inside its masks. Travel is clamped to the edges, but
+/// drawing is cut where it crosses them: the pen lifts for the part outside and comes
+/// back down where the line re-enters.
 pub fn clamp_movements(
     movements: Vec<Movement>,
     dimensions: Vec2D,
+    masks: &Masks,
     mode: Position,
-) -> Vec<Movement> {
+) -> (Vec<Movement>, ClipReport) {
     let mut new_movements = Vec::new();
+    let mut report = ClipReport::default();
 
+    // where the job thinks it is, which may be off the bed
     let mut position = Vec2D::default();
+    // where the machine has actually been sent
+    let mut machine = Vec2D::default();
+
+    let clamp = |point: Vec2D| Vec2D {
+        x: f32::min(f32::max(0.0, point.x), dimensions.x),
+        y: f32::min(f32::max(0.0, point.y), dimensions.y),
+    };
+    let in_bounds = |point: &Vec2D| clamp(*point) == *point;
 
     for mv in movements {
-        let dest = match mode {
-            Position::Absolute => mv.dest,
-            Position::Relative => position + mv.dest,
+        let (dest, center) = match mode {
+            Position::Absolute => (mv.dest, mv.arc.map(|arc| arc.center)),
+            Position::Relative => (position + mv.dest, mv.arc.map(|arc| position + arc.center)),
+        };
+        let at = |dest: Vec2D, pen_down: bool, arc: Option<ArcDescription>| Movement {
+            dest,
+            pen_down,
+            arc,
+            pen: mv.pen.clone(),
         };
 
-        let clamped = Vec2D {
-            x: f32::min(f32::max(0.0, dest.x), dimensions.x),
-            y: f32::min(f32::max(0.0, dest.y), dimensions.y),
+        if !mv.pen_down {
+            push_movement(
+                &mut new_movements,
+                &mut machine,
+                mode,
+                at(clamp(dest), false, None),
+            );
+            position = dest;
+            continue;
+        }
+
+        // an arc that bulges off the bed can't be cut as one move, so it becomes the
+        // straight segments it would have drawn
+        let points: Vec<Vec2D> = match (mv.arc, center) {
+            (Some(arc), Some(center)) => {
+                let flattened = arcs::flatten(
+                    position,
+                    dest,
+                    center,
+                    arc.clockwise,
+                    arcs::FLATTEN_TOLERANCE,
+                );
+                let allowed = |point: &Vec2D| in_bounds(point) && masks.allows(*point);
+                if allowed(&position) && flattened.iter().all(allowed) {
+                    let arc = Some(ArcDescription { center, ..arc });
+                    push_movement(&mut new_movements, &mut machine, mode, at(dest, true, arc));
+                    position = dest;
+                    continue;
+                }
+                flattened
+            }
+            _ => vec![dest],
         };
 
-        match mode {
-            Position::Absolute => new_movements.push(Movement {
-                dest: clamped,
-                pen_down: mv.pen_down,
-            }),
-            Position::Relative => new_movements.push(Movement {
-                dest: clamped - position,
-                pen_down: mv.pen_down,
-            }),
+        for point in points {
+            let length = position.distance(point);
+            let pieces = match clip_segment(position, point, dimensions) {
+                Some((enter, leave)) => {
+                    masks::intersect(masks.drawable(position, point), enter, leave)
+                }
+                None => Vec::new(),
+            };
+            let along = |t: f32| Vec2D {
+                x: position.x + (point.x - position.x) * t,
+                y: position.y + (point.y - position.y) * t,
+            };
+
+            for (enter, leave) in pieces.iter().copied() {
+                // keep the ends exact when nothing was cut, so they still line up
+                let start = if enter > 0.0 { along(enter) } else { position };
+                let end = if leave < 1.0 { along(leave) } else { point };
+                if start != machine {
+                    push_movement(
+                        &mut new_movements,
+                        &mut machine,
+                        mode,
+                        at(start, false, None),
+                    );
+                }
+                push_movement(&mut new_movements, &mut machine, mode, at(end, true, None));
+            }
+
+            let kept: f32 = pieces.iter().map(|(enter, leave)| leave - enter).sum();
+            if kept < 1.0 {
+                report.segments_clipped += 1;
+                report.distance_clipped += length * (1.0 - kept);
+            }
+            position = point;
         }
+    }
+
+    (new_movements, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::Marlin;
 
-        position = clamped;
+    #[test]
+    fn layers_without_pens_keep_the_loaded_one() {
+        let line = |x: f32| {
+            vec![Movement {
+                dest: Vec2D { x, y: 0.0 },
+                pen_down: true,
+                ..Default::default()
+            }]
+        };
+        let mut red = Layer::new("#ff0000", line(1.0));
+        red.pen = Some("red".to_string());
+        let layers = [
+            Layer::new("#123456", line(2.0)),
+            red,
+            Layer::new("#654321", line(3.0)),
+        ];
+        let feedrates = Feedrates {
+            draw: 1000,
+            travel: 3000,
+            rapid_travel: true,
+        };
+
+        let changes: Vec<GCode> = layers_to_gcode(&layers, &feedrates, None)
+            .into_iter()
+            .filter(|op| matches!(op, GCode::ToolChange(_)))
+            .collect();
+        assert!(changes.is_empty());
     }
 
-    new_movements
+    #[test]
+    fn repeats_travel_back_to_the_start() {
+        let corner = [(10.0, 0.0), (10.0, 10.0)]
+            .into_iter()
+            .map(|(x, y)| Movement {
+                dest: Vec2D { x, y },
+                pen_down: true,
+                ..Default::default()
+            })
+            .collect();
+        let layer = Layer {
+            repeat: 2,
+            ..Layer::new("twice", corner)
+        };
+        let feedrates = Feedrates {
+            draw: 1000,
+            travel: 3000,
+            rapid_travel: true,
+        };
+
+        let gcode = layers_to_gcode(&[layer], &feedrates, None);
+        let second = gcode.iter().rposition(|op| *op == GCode::Activate).unwrap();
+        assert_eq!(
+            gcode[second - 2..second],
+            [
+                GCode::Deactivate,
+                GCode::LinearMove {
+                    target: Vec3 {
+                        x: Some(0.0),
+                        y: Some(0.0),
+                        z: None,
+                    },
+                    feedrate: Some(3000),
+                },
+            ]
+        );
+    }
+
+    const BED: Vec2D = Vec2D { x: 10.0, y: 10.0 };
+
+    // (x, y, pen down) for each of `movements`
+    fn points(movements: &[Movement]) -> Vec<(f32, f32, bool)> {
+        movements
+            .iter()
+            .map(|mv| (mv.dest.x, mv.dest.y, mv.pen_down))
+            .collect()
+    }
+
+    fn moves(points: &[(f32, f32, bool)]) -> Vec<Movement> {
+        points
+            .iter()
+            .map(|&(x, y, pen_down)| Movement {
+                dest: Vec2D { x, y },
+                pen_down,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn segments_off_the_bed_are_dropped() {
+        assert_eq!(
+            clip_segment(Vec2D { x: 20.0, y: 0.0 }, Vec2D { x: 20.0, y: 20.0 }, BED),
+            None
+        );
+
+        let (clamped, report) = clamp_movements(
+            moves(&[(20.0, 0.0, false), (20.0, 20.0, true)]),
+            BED,
+            &Masks::default(),
+            Position::Absolute,
+        );
+        assert_eq!(points(&clamped), [(10.0, 0.0, false)]);
+        assert_eq!(report.segments_clipped, 1);
+        assert_eq!(report.distance_clipped, 20.0);
+    }
+
+    #[test]
+    fn segments_crossing_the_bed_keep_the_part_on_it() {
+        assert_eq!(
+            clip_segment(Vec2D { x: -5.0, y: 5.0 }, Vec2D { x: 15.0, y: 5.0 }, BED),
+            Some((0.25, 0.75))
+        );
+
+        let (clamped, report) = clamp_movements(
+            moves(&[(-5.0, 5.0, false), (15.0, 5.0, true), (5.0, 9.0, true)]),
+            BED,
+            &Masks::default(),
+            Position::Absolute,
+        );
+        // the pen comes up where the line leaves and goes back down where it re-enters
+        assert_eq!(
+            points(&clamped),
+            [
+                (0.0, 5.0, false),
+                (10.0, 5.0, true),
+                (10.0, 7.0, false),
+                (5.0, 9.0, true),
+            ]
+        );
+        assert_eq!(report.segments_clipped, 2);
+    }
+
+    #[test]
+    fn relative_output_follows_the_machine() {
+        let (clamped, _) = clamp_movements(
+            moves(&[(-5.0, 5.0, false), (20.0, 0.0, true), (-20.0, 0.0, false)]),
+            BED,
+            &Masks::default(),
+            Position::Relative,
+        );
+        // the job thinks it's back at (-5, 5), but the machine only went to (0, 5)
+        assert_eq!(
+            points(&clamped),
+            [(0.0, 5.0, false), (10.0, 0.0, true), (-10.0, 0.0, false)]
+        );
+    }
+
+    #[test]
+    fn z_axis_pens_are_converted_to_inches() {
+        let marlin = Marlin {
+            pen_up: 5.0,
+            pen_down: 0.0,
+            filament_change: false,
+        };
+        let pen = PenProfile {
+            lift_speed: Some(508),
+            ..marlin.default_pen()
+        };
+        let gcode = vec![
+            GCode::Activate,
+            GCode::LinearDraw {
+                target: Vec3 {
+                    x: Some(25.4),
+                    y: Some(50.8),
+                    z: None,
+                },
+                feedrate: Some(1270),
+            },
+            GCode::Deactivate,
+        ];
+
+        let program = to_program(
+            &gcode,
+            &marlin,
+            &pen,
+            std::slice::from_ref(&pen),
+            3,
+            Units::Inches,
+            None,
+        )
+        .unwrap();
+        assert!(program.contains(&"G20".to_string()));
+        assert!(program.contains(&"G1 F20 Z0".to_string()));
+        assert!(program.contains(&"G1 F50 X1 Y2".to_string()));
+        assert!(program.contains(&"G1 F20 Z0.197".to_string()));
+        assert!(!program.iter().any(|line| line.contains("Z5")));
+
+        let job = JobInfo::default();
+        let program = to_program(
+            &gcode,
+            &marlin,
+            &pen,
+            std::slice::from_ref(&pen),
+            3,
+            Units::Inches,
+            Some(&job),
+        )
+        .unwrap();
+        assert!(program[0].ends_with("to X1.000 Y2.000 in"));
+    }
 }
@@ -1,15 +1,31 @@
 use axum::body::{Bytes, Full};
 use axum::http::{header, Response, StatusCode};
-use axum::{extract::Json, extract::Path, extract::State, routing::get, routing::post, Router};
+use axum::{
+    extract::Json, extract::Path, extract::Query, extract::RawQuery, extract::State, routing::get,
+    routing::post, Router,
+};
 
 use config::Config;
-use gcode_wrangler::models::{MachineDetails, Movement};
+use gcode_wrangler::dialect::{Dialect, DialectRegistry};
+use gcode_wrangler::dxf;
+use gcode_wrangler::fill;
+use gcode_wrangler::hpgl;
+use gcode_wrangler::masks::{Masks, Zone};
+use gcode_wrangler::models::{
+    InputUnits, JobInfo, JobPayload, JobReport, Layer, MachineDetails, Movement, PenProfile, Vec2D,
+};
+use gcode_wrangler::optimize::{self, MergeReport, SimplifyReport, TravelReport};
+use gcode_wrangler::sanitize;
+use gcode_wrangler::svg;
+use gcode_wrangler::transform::{self, Transform};
 use gcode_wrangler::{
-    clamp_movements, to_gcode, to_program, GCode, PortCmd, Position, SerialChannel,
+    arcs, clamp_movements, layers_to_gcode, to_program, without_comments, ChannelHandles,
+    ChannelStatus, GCode, PortCmd, Position, SerialChannel, Units,
 };
 use image::imageops::flip_vertical_in_place;
 use image::{ImageOutputFormat, Rgba, RgbaImage};
-use imageproc::drawing::{draw_line_segment_mut, Blend};
+use imageproc::drawing::{draw_hollow_circle_mut, draw_line_segment_mut, Blend};
+use serde::Deserialize;
 use std::collections::hash_map::{DefaultHasher, HashMap};
 use std::hash::{Hash, Hasher};
 use std::io::Cursor;
@@ -26,16 +42,84 @@ type Handle = u64;
 const IMAGE_SCALE: f32 = 4.0;
 const DRAW_COLOR: Rgba<u8> = Rgba([0u8, 0u8, 0u8, 255u8]);
 const MOVE_COLOR: Rgba<u8> = Rgba([235u8, 197u8, 103u8, 255u8]);
+const INCLUDE_COLOR: Rgba<u8> = Rgba([92u8, 184u8, 92u8, 255u8]);
+const EXCLUDE_COLOR: Rgba<u8> = Rgba([217u8, 83u8, 79u8, 255u8]);
+// pixels per inch for jobs in px that don't say
+const DEFAULT_DPI: f32 = 96.0;
 
 #[derive(Clone)]
 pub struct AppState {
     movements: Arc<Mutex<HashMap<Handle, Vec<Movement>>>>,
     cached_gcode: Arc<Mutex<HashMap<Handle, Vec<GCode>>>>,
+    jobs: Arc<Mutex<HashMap<Handle, JobInfo>>>,
+    reports: Arc<Mutex<HashMap<Handle, JobReport>>>,
+    masks: Arc<Mutex<HashMap<Handle, Masks>>>,
     machine_details: MachineDetails,
+    // for rendering jobs for controllers other than the attached one, from the same
+    // flat settings the machine was configured with
+    dialects: Arc<DialectRegistry>,
+    settings: Arc<HashMap<String, String>>,
     progress: Receiver<usize>,
+    channel_status: Receiver<ChannelStatus>,
     cmd_channel: Sender<PortCmd>,
 }
 
+// per-job overrides for the machine defaults
+#[derive(Deserialize)]
+struct UploadOptions {
+    // what the job's coordinates are in; millimetres if absent. Masks in the job are
+    // always in millimetres.
+    units: Option<InputUnits>,
+    // for px, and 96 if absent
+    dpi: Option<f32>,
+    draw_speed: Option<u32>,
+    travel_speed: Option<u32>,
+    rapid_travel: Option<bool>,
+    // defaults to on when the machine has an arc_tolerance
+    fit_arcs: Option<bool>,
+    // reorder strokes to cut down on pen-up travel
+    optimize: Option<bool>,
+    // drop points that stray less than this, in mm, from a straight line
+    simplify_tolerance: Option<f32>,
+    // join strokes whose ends are this close, in mm, and drop retraced segments
+    merge_tolerance: Option<f32>,
+    // the rest are in millimetres, after any conversion from the job's units.
+    // Mirroring, scaling and rotation (counter-clockwise, in degrees) all happen about
+    // the middle of the drawing, in that order, and then it's moved
+    mirror_x: Option<bool>,
+    mirror_y: Option<bool>,
+    scale: Option<f32>,
+    rotate: Option<f32>,
+    translate_x: Option<f32>,
+    translate_y: Option<f32>,
+    // scale and centre the drawing to fill the bed less this margin, in mm
+    fit_margin: Option<f32>,
+    // when fitting, turn the drawing a quarter turn if it comes out bigger that way
+    auto_rotate: Option<bool>,
+    // only used to label the program
+    title: Option<String>,
+    user: Option<String>,
+}
+
+// for the file formats that have curves in them
+#[derive(Deserialize)]
+struct ImportOptions {
+    // how far, in mm on the bed, flattened curves may stray from the real ones
+    curve_tolerance: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct RunOptions {
+    pen: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DownloadOptions {
+    // the attached machine's if absent
+    flavor: Option<String>,
+    pen: Option<String>,
+}
+
 #[tokio::main]
 async fn main() {
     let settings = Config::builder()
@@ -44,27 +128,42 @@ async fn main() {
         .build()
         .unwrap();
 
-    let machine: MachineDetails = settings
-        .try_deserialize::<HashMap<String, String>>()
+    // pens and masks are tables, so they're read separately from the flat settings
+    let pens: Option<Vec<PenProfile>> = settings.get("pens").ok();
+    let masks: Option<Masks> = settings.get("masks").ok();
+
+    let dialects = Arc::new(DialectRegistry::default());
+    let settings: HashMap<String, String> = settings
+        .try_deserialize::<HashMap<String, config::Value>>()
         .unwrap()
-        .into();
+        .into_iter()
+        .filter_map(|(key, value)| value.into_string().ok().map(|value| (key, value)))
+        .collect();
+    let mut machine = MachineDetails::from_settings(settings.clone(), &dialects);
 
-    let maybe_channel: Result<
-        (
-            tokio::sync::watch::Receiver<usize>,
-            Sender<PortCmd>,
-            SerialChannel,
-        ),
-        serialport::Error,
-    > = SerialChannel::new(&machine);
+    if let Some(pens) = pens.filter(|pens| !pens.is_empty()) {
+        machine.pens = pens;
+    }
+    if let Some(masks) = masks {
+        machine.masks = masks;
+    }
 
-    let (progress, cmd, mut channel) = maybe_channel.expect("failed to open serial port");
+    let maybe_channel: Result<ChannelHandles, serialport::Error> = SerialChannel::new(&machine);
+
+    let (progress, channel_status, cmd, mut channel) =
+        maybe_channel.expect("failed to open serial port");
 
     let state = AppState {
         machine_details: machine,
+        dialects,
+        settings: Arc::new(settings),
         movements: Default::default(),
         cached_gcode: Default::default(),
+        jobs: Default::default(),
+        reports: Default::default(),
+        masks: Default::default(),
         progress,
+        channel_status,
         cmd_channel: cmd,
     };
 
@@ -75,10 +174,17 @@ async fn main() {
     let app = Router::new()
         .route("/run/:handle", get(get_run).post(post_run))
         .route("/rendered/:handle", get(get_analysis))
+        .route("/report/:handle", get(get_report))
         .route("/movements", post(post_movements))
+        .route("/svg", post(post_svg))
+        .route("/hpgl", post(post_hpgl))
+        .route("/dxf", post(post_dxf))
+        .route("/gcode", post(post_gcode))
+        .route("/gcode/:handle", get(get_gcode))
         .route("/pause", post(post_pause))
         .route("/resume", post(post_resume))
         .route("/cancel", post(post_cancel))
+        .route("/status", get(get_status))
         .route("/machine", get(get_machine))
         .layer(
             TraceLayer::new_for_http()
@@ -101,31 +207,393 @@ async fn get_machine(State(state): State<AppState>) -> Json<MachineDetails> {
 
 async fn post_movements(
     State(state): State<AppState>,
-    Json(movements): Json<Vec<Movement>>,
-) -> String {
+    Query(options): Query<UploadOptions>,
+    RawQuery(query): RawQuery,
+    Json(payload): Json<JobPayload>,
+) -> (StatusCode, String) {
+    let mut s = DefaultHasher::new();
+    payload.hash(&mut s);
+    query.hash(&mut s);
+    let (layers, job_masks) = payload.into_parts();
+    store_job(state, options, s.finish(), layers, job_masks).await
+}
+
+async fn post_svg(
+    State(state): State<AppState>,
+    Query(options): Query<UploadOptions>,
+    Query(import_options): Query<ImportOptions>,
+    RawQuery(query): RawQuery,
+    body: String,
+) -> (StatusCode, String) {
+    let mut s = DefaultHasher::new();
+    body.hash(&mut s);
+    query.hash(&mut s);
+
+    let machine = &state.machine_details;
+    let tolerance = import_options
+        .curve_tolerance
+        .unwrap_or(svg::CURVE_TOLERANCE);
+    let mut layers = match svg::import(&body, machine.dimensions, tolerance) {
+        Ok(layers) => layers,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+    };
+    // colours without a pen of their own keep whichever pen is loaded rather than
+    // stopping for a change, as any layer without a pen does
+    for layer in layers.iter_mut() {
+        layer.pen = svg::pen_for_color(&machine.pens, &layer.name).map(|pen| pen.name.clone());
+    }
+
+    // the import has already put the drawing on the bed in millimetres
+    let options = UploadOptions {
+        units: None,
+        dpi: None,
+        ..options
+    };
+    store_job(state, options, s.finish(), layers, Masks::default()).await
+}
+
+async fn post_dxf(
+    State(state): State<AppState>,
+    Query(options): Query<UploadOptions>,
+    Query(import_options): Query<ImportOptions>,
+    RawQuery(query): RawQuery,
+    body: String,
+) -> (StatusCode, String) {
     let mut s = DefaultHasher::new();
-    movements.hash(&mut s);
+    body.hash(&mut s);
+    query.hash(&mut s);
+
+    let tolerance = import_options
+        .curve_tolerance
+        .unwrap_or(svg::CURVE_TOLERANCE);
+    let mut layers = match dxf::import(&body, tolerance) {
+        Ok(layers) => layers,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+    };
+    // a DXF layer is drawn with the pen of the same name, if there is one
+    for layer in layers.iter_mut() {
+        layer.pen = state
+            .machine_details
+            .pen(Some(&layer.name))
+            .map(|pen| pen.name.clone());
+    }
+
+    // the import has already converted $INSUNITS to millimetres
+    let options = UploadOptions {
+        units: None,
+        dpi: None,
+        ..options
+    };
+    store_job(state, options, s.finish(), layers, Masks::default()).await
+}
+
+async fn post_hpgl(
+    State(state): State<AppState>,
+    Query(options): Query<UploadOptions>,
+    RawQuery(query): RawQuery,
+    body: String,
+) -> (StatusCode, String) {
+    let mut s = DefaultHasher::new();
+    body.hash(&mut s);
+    query.hash(&mut s);
     let hash = s.finish();
 
-    let clamped_movements = clamp_movements(
-        movements,
-        state.machine_details.dimensions,
-        Position::Absolute,
-    );
+    let plot = match hpgl::import(&body, &state.machine_details.pens) {
+        Ok(plot) => plot,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+    };
+    if !plot.skipped.is_empty() {
+        tracing::info!(
+            "Skipped {} HPGL instructions in job {hash}: {}",
+            plot.skipped.len(),
+            plot.skipped.join(" ")
+        );
+    }
+
+    // whichever pen the plot selects first is the one it starts with
+    let mut layer = Layer::new("hpgl", plot.movements);
+    layer.pen = layer.movements.iter().find_map(|mv| mv.pen.clone());
+
+    // the import has already scaled plotter units to millimetres
+    let options = UploadOptions {
+        units: None,
+        dpi: None,
+        ..options
+    };
+    store_job(state, options, hash, vec![layer], Masks::default()).await
+}
+
+async fn post_gcode(State(state): State<AppState>, body: String) -> (StatusCode, String) {
+    let mut s = DefaultHasher::new();
+    body.hash(&mut s);
+    let hash = s.finish();
+
+    let checked = match sanitize::check(&body, &state.machine_details) {
+        Ok(checked) => checked,
+        Err(rejections) => {
+            let lines: Vec<String> = rejections.iter().map(|r| r.to_string()).collect();
+            return (StatusCode::UNPROCESSABLE_ENTITY, lines.join("\n"));
+        }
+    };
 
     state
         .cached_gcode
         .lock()
         .unwrap()
-        .insert(hash, to_gcode(&clamped_movements, Position::Absolute));
-
+        .insert(hash, checked.gcode);
     state
         .movements
         .lock()
         .unwrap()
-        .insert(hash, clamped_movements);
+        .insert(hash, checked.movements);
+    state.jobs.lock().unwrap().insert(
+        hash,
+        JobInfo {
+            handle: Some(hash.to_string()),
+            ..JobInfo::default()
+        },
+    );
+
+    (StatusCode::OK, hash.to_string())
+}
 
-    hash.to_string()
+// Runs the pipeline on the blocking pool, since big jobs can keep it busy for a while
+async fn store_job(
+    state: AppState,
+    options: UploadOptions,
+    hash: u64,
+    layers: Vec<Layer>,
+    job_masks: Masks,
+) -> (StatusCode, String) {
+    tokio::task::spawn_blocking(move || process_job(&state, options, hash, layers, &job_masks))
+        .await
+        .unwrap_or_else(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// Everything after parsing that turns a job into G-code and a preview under `hash`
+fn process_job(
+    state: &AppState,
+    options: UploadOptions,
+    hash: u64,
+    mut layers: Vec<Layer>,
+    job_masks: &Masks,
+) -> (StatusCode, String) {
+    let machine = &state.machine_details;
+    let masks = machine.masks.with_job(job_masks);
+
+    if let Some(layer) = layers.iter().find(|layer| {
+        layer
+            .pen
+            .as_deref()
+            .is_some_and(|pen| machine.pen(Some(pen)).is_none())
+    }) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Unknown pen for layer {}", layer.name),
+        );
+    }
+    if let Some(layer) = layers
+        .iter()
+        .find(|layer| !layer.shapes.is_empty() && layer.fill.is_none())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Layer {} has shapes but no fill", layer.name),
+        );
+    }
+    // any closer and the lines couldn't be told apart in the program anyway
+    let min_spacing = 10f32.powi(-(machine.precision as i32));
+    if let Some(layer) = layers.iter().find(|layer| {
+        layer
+            .fill
+            .is_some_and(|hatch| hatch.spacing.is_nan() || hatch.spacing < min_spacing)
+    }) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Layer {} needs a fill spacing of at least {min_spacing}mm",
+                layer.name
+            ),
+        );
+    }
+
+    let feedrates = machine.feedrates.with_overrides(
+        options.draw_speed,
+        options.travel_speed,
+        options.rapid_travel,
+    );
+
+    let mut report = JobReport::default();
+
+    // every layer gets the same transform, so they stay lined up with each other
+    let units = options
+        .units
+        .unwrap_or_default()
+        .scale(options.dpi.unwrap_or(DEFAULT_DPI), machine.dimensions);
+    let mut chosen = Transform::scale(units.x, units.y);
+    if let Some(center) =
+        transform::bounds(layers.iter().flat_map(|layer| &layer.movements)).map(|(low, high)| {
+            chosen.apply(Vec2D {
+                x: (low.x + high.x) / 2.0,
+                y: (low.y + high.y) / 2.0,
+            })
+        })
+    {
+        let mirror = |mirrored: Option<bool>| if mirrored.unwrap_or(false) { -1.0 } else { 1.0 };
+        let scale = options.scale.unwrap_or(1.0);
+        chosen = chosen.then(
+            &Transform::scale(mirror(options.mirror_x), mirror(options.mirror_y))
+                .then(&Transform::scale(scale, scale))
+                .then(&Transform::rotate(options.rotate.unwrap_or(0.0)))
+                .around(center)
+                .then(&Transform::translate(
+                    options.translate_x.unwrap_or(0.0),
+                    options.translate_y.unwrap_or(0.0),
+                )),
+        );
+    }
+    if let Some(margin) = options.fit_margin {
+        let placed: Vec<Movement> = layers
+            .iter()
+            .flat_map(|layer| chosen.apply_movements(&layer.movements))
+            .collect();
+        if let Some(bounds) = transform::bounds(&placed) {
+            chosen = chosen.then(&transform::fit_to_bed(
+                bounds,
+                machine.dimensions,
+                margin,
+                options.auto_rotate.unwrap_or(false),
+            ));
+        }
+    }
+    if chosen != Transform::identity() {
+        for layer in layers.iter_mut() {
+            layer.movements = chosen.apply_movements(&layer.movements);
+        }
+        report.transform = Some(chosen);
+    }
+
+    // after the transform, so spacing and inset are in millimetres on the bed
+    for layer in layers.iter_mut() {
+        if let Some(hatch) = layer.fill {
+            let shapes = fill::fill_shapes(
+                layer
+                    .shapes
+                    .iter()
+                    .map(|shape| shape.iter().map(|point| chosen.apply(*point)).collect())
+                    .collect(),
+                &layer.movements,
+            );
+            match fill::hatch(&shapes, &hatch) {
+                Ok(hatched) => layer.movements.extend(hatched),
+                Err(e) => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        format!("Layer {}: {e}", layer.name),
+                    )
+                }
+            }
+        }
+    }
+
+    for layer in layers.iter_mut() {
+        let (clamped, clip) = clamp_movements(
+            std::mem::take(&mut layer.movements),
+            machine.dimensions,
+            &masks,
+            Position::Absolute,
+        );
+        layer.movements = clamped;
+        report.clip.segments_clipped += clip.segments_clipped;
+        report.clip.distance_clipped += clip.distance_clipped;
+
+        if let Some(tolerance) = options.simplify_tolerance {
+            let (simplified, simplify) = optimize::simplify(&layer.movements, tolerance);
+            layer.movements = simplified;
+            let total = report.simplify.get_or_insert_with(SimplifyReport::default);
+            total.points_before += simplify.points_before;
+            total.points_after += simplify.points_after;
+        }
+
+        // merge first, so the reordering works with whole strokes
+        if let Some(tolerance) = options.merge_tolerance {
+            let (merged, merge) = optimize::merge(&layer.movements, tolerance);
+            layer.movements = merged;
+            let total = report.merge.get_or_insert_with(MergeReport::default);
+            total.pen_lifts_before += merge.pen_lifts_before;
+            total.pen_lifts_after += merge.pen_lifts_after;
+            total.overdraw_removed += merge.overdraw_removed;
+        }
+
+        if options.optimize.unwrap_or(false) {
+            let (reordered, travel) = optimize::reorder(&layer.movements);
+            layer.movements = reordered;
+            let total = report.travel.get_or_insert_with(TravelReport::default);
+            total.before += travel.before;
+            total.after += travel.after;
+        }
+
+        // last, since every pass before this one is free to move travel around
+        if !masks.exclude.is_empty() {
+            layer.movements = masks.route_travel(&layer.movements, machine.dimensions);
+        }
+    }
+    state.masks.lock().unwrap().insert(hash, masks);
+
+    if report.clip.segments_clipped > 0 {
+        tracing::info!(
+            "Clipped {} segments ({:.0}mm) off the bed in job {hash}",
+            report.clip.segments_clipped,
+            report.clip.distance_clipped
+        );
+    }
+    if let Some(simplify) = report.simplify {
+        tracing::info!(
+            "Simplified job {hash}: {} points -> {}",
+            simplify.points_before,
+            simplify.points_after
+        );
+    }
+    if let Some(travel) = report.travel {
+        tracing::info!(
+            "Reordered job {hash}: travel {:.0}mm -> {:.0}mm",
+            travel.before,
+            travel.after
+        );
+    }
+    state.reports.lock().unwrap().insert(hash, report);
+
+    let arc_tolerance = match options.fit_arcs {
+        _ if !machine.flavor.capabilities().arcs => None,
+        Some(true) => Some(machine.arc_tolerance.unwrap_or(arcs::FIT_TOLERANCE)),
+        Some(false) => None,
+        None => machine.arc_tolerance,
+    };
+
+    state
+        .cached_gcode
+        .lock()
+        .unwrap()
+        .insert(hash, layers_to_gcode(&layers, &feedrates, arc_tolerance));
+
+    state.jobs.lock().unwrap().insert(
+        hash,
+        JobInfo {
+            title: options.title,
+            user: options.user,
+            handle: Some(hash.to_string()),
+            pen: layers.first().and_then(|layer| layer.pen.clone()),
+        },
+    );
+
+    // the preview shows every layer, in order
+    let movements: Vec<Movement> = layers
+        .into_iter()
+        .flat_map(|layer| layer.movements)
+        .collect();
+    state.movements.lock().unwrap().insert(hash, movements);
+
+    (StatusCode::OK, hash.to_string())
 }
 
 async fn post_pause(State(state): State<AppState>) {
@@ -140,21 +608,125 @@ async fn post_cancel(State(state): State<AppState>) {
     state.cmd_channel.send(PortCmd::CANCEL).await.unwrap();
 }
 
-async fn post_run(State(state): State<AppState>, Path(handle): Path<Handle>) -> StatusCode {
-    let flavor = state.machine_details.flavor;
-    let program = state
+// The program for a job as `flavor` would have it, starting with `pen` loaded, or the
+// status to give instead
+fn render_job(
+    state: &AppState,
+    handle: Handle,
+    flavor: &dyn Dialect,
+    pen: Option<String>,
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let machine = &state.machine_details;
+    let job = state.jobs.lock().unwrap().get(&handle).cloned();
+
+    // this machine's pens and units only make sense on its own controller, so another
+    // flavor gets its own default pen whatever the job asks for
+    let foreign = flavor.name() != machine.flavor.name();
+    let (pens, units) = if foreign {
+        (vec![flavor.default_pen()], Units::Millimeters)
+    } else {
+        (machine.pens.clone(), machine.units)
+    };
+    // a layered job knows which pen it starts with
+    let requested = pen.or_else(|| job.as_ref().and_then(|job| job.pen.clone()));
+    let pen = match requested {
+        Some(name) if !foreign => pens.iter().find(|pen| pen.name == name),
+        _ => pens.first(),
+    }
+    .ok_or((StatusCode::BAD_REQUEST, "Unknown pen".to_string()))?;
+
+    let gcode = state
         .cached_gcode
         .lock()
         .unwrap()
         .get(&handle)
-        .map(|gcode| to_program(gcode, flavor));
+        .cloned()
+        .ok_or((StatusCode::NOT_FOUND, String::new()))?;
+
+    let program = if flavor.capabilities().comments {
+        to_program(
+            &gcode,
+            flavor,
+            pen,
+            &pens,
+            machine.precision,
+            units,
+            job.as_ref(),
+        )
+    } else {
+        to_program(
+            &without_comments(&gcode),
+            flavor,
+            pen,
+            &pens,
+            machine.precision,
+            units,
+            None,
+        )
+    };
+    program.map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+}
+
+async fn post_run(
+    State(state): State<AppState>,
+    Path(handle): Path<Handle>,
+    Query(options): Query<RunOptions>,
+) -> (StatusCode, String) {
+    let flavor = state.machine_details.flavor.clone();
+    let program = match render_job(&state, handle, flavor.as_ref(), options.pen) {
+        Ok(program) => program,
+        Err(status) => return status,
+    };
+
+    match state.cmd_channel.send(PortCmd::SEND(program)).await {
+        Ok(_) => (StatusCode::OK, String::new()),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    }
+}
+
+// Downloads a job's program, for the attached machine or any other flavor
+async fn get_gcode(
+    State(state): State<AppState>,
+    Path(handle): Path<Handle>,
+    Query(options): Query<DownloadOptions>,
+) -> Response<Full<Bytes>> {
+    let attached = state.machine_details.flavor.clone();
+    let flavor = match options.flavor {
+        None => Some(attached),
+        Some(name) if name.eq_ignore_ascii_case(attached.name()) => Some(attached),
+        Some(name) => state
+            .dialects
+            .names()
+            .into_iter()
+            .find(|known| known.eq_ignore_ascii_case(&name))
+            .and_then(|known| state.dialects.build(known, &state.settings)),
+    };
+    let program = match flavor {
+        Some(flavor) => render_job(&state, handle, flavor.as_ref(), options.pen),
+        None => {
+            let mut names = state.dialects.names();
+            names.sort();
+            Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown flavor; try one of {}", names.join(", ")),
+            ))
+        }
+    };
 
     match program {
-        Some(program) => match state.cmd_channel.send(PortCmd::SEND(program)).await {
-            Ok(_) => StatusCode::OK,
-            Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        },
-        None => StatusCode::NOT_FOUND,
+        Ok(program) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{handle}.gcode\""),
+            )
+            .body(Full::from(program.join("\n") + "\n"))
+            .unwrap(),
+        Err((status, message)) => Response::builder()
+            .status(status)
+            .body(Full::from(message))
+            .unwrap(),
     }
 }
 
@@ -162,6 +734,46 @@ async fn get_run(State(state): State<AppState>) -> Json<usize> {
     axum::Json(*state.progress.borrow())
 }
 
+async fn get_report(
+    State(state): State<AppState>,
+    Path(handle): Path<Handle>,
+) -> Result<Json<JobReport>, StatusCode> {
+    match state.reports.lock().unwrap().get(&handle) {
+        Some(report) => Ok(axum::Json(report.clone())),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// resume also clears a wait for a pen change
+async fn get_status(State(state): State<AppState>) -> Json<ChannelStatus> {
+    axum::Json(*state.channel_status.borrow())
+}
+
+fn draw_zone(canvas: &mut Blend<RgbaImage>, zone: &Zone, color: Rgba<u8>) {
+    match zone {
+        Zone::Circle { center, radius } => draw_hollow_circle_mut(
+            canvas,
+            (
+                (center.x * IMAGE_SCALE) as i32,
+                (center.y * IMAGE_SCALE) as i32,
+            ),
+            (radius * IMAGE_SCALE) as i32,
+            color,
+        ),
+        Zone::Polygon { points } => {
+            for (i, start) in points.iter().enumerate() {
+                let end = points[(i + 1) % points.len()];
+                draw_line_segment_mut(
+                    canvas,
+                    (start.x * IMAGE_SCALE, start.y * IMAGE_SCALE),
+                    (end.x * IMAGE_SCALE, end.y * IMAGE_SCALE),
+                    color,
+                );
+            }
+        }
+    }
+}
+
 async fn get_analysis(
     State(state): State<AppState>,
     Path(handle): Path<Handle>,
@@ -175,27 +787,47 @@ async fn get_analysis(
             );
             let mut canvas = Blend(image);
 
-            let mut start_position = (0.0, 0.0);
-
-            for movement in movements.iter() {
-                let dest = (
-                    // using absolute coordinates always for now
-                    (movement.dest.x * IMAGE_SCALE),
-                    (movement.dest.y * IMAGE_SCALE),
-                );
+            if let Some(masks) = state.masks.lock().unwrap().get(&handle) {
+                for (zone, color) in masks
+                    .include
+                    .iter()
+                    .chain(masks.within.iter())
+                    .map(|zone| (zone, INCLUDE_COLOR))
+                    .chain(masks.exclude.iter().map(|zone| (zone, EXCLUDE_COLOR)))
+                {
+                    draw_zone(&mut canvas, zone, color);
+                }
+            }
 
-                draw_line_segment_mut(
-                    &mut canvas,
-                    start_position,
-                    dest,
-                    if movement.pen_down {
-                        DRAW_COLOR
-                    } else {
-                        MOVE_COLOR
-                    },
-                );
+            let mut position = Vec2D::default();
 
-                start_position = dest;
+            for movement in movements.iter() {
+                // using absolute coordinates always for now
+                let points = match movement.arc.filter(|_| movement.pen_down) {
+                    Some(arc) => arcs::flatten(
+                        position,
+                        movement.dest,
+                        arc.center,
+                        arc.clockwise,
+                        arcs::FLATTEN_TOLERANCE,
+                    ),
+                    None => vec![movement.dest],
+                };
+
+                for point in points {
+                    draw_line_segment_mut(
+                        &mut canvas,
+                        (position.x * IMAGE_SCALE, position.y * IMAGE_SCALE),
+                        (point.x * IMAGE_SCALE, point.y * IMAGE_SCALE),
+                        if movement.pen_down {
+                            DRAW_COLOR
+                        } else {
+                            MOVE_COLOR
+                        },
+                    );
+
+                    position = point;
+                }
             }
 
             flip_vertical_in_place(&mut canvas.0);
@@ -0,0 +1,384 @@
+use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Movement, Vec2D};
+
+// how far routed travel keeps from the edge of an exclusion zone, in mm
+const ROUTE_MARGIN: f32 = 1.0;
+
+// sides of the polygon travel is routed around in place of a circle
+const CIRCLE_SIDES: usize = 16;
+
+// overlaps shorter than this, in mm, are just grazing an edge
+const GRAZE: f32 = 1e-3;
+
+/// Fractions along a segment, or any other stretches of a line, sorted and not
+/// overlapping
+pub type Intervals = Vec<(f32, f32)>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Zone {
+    // any simple polygon, in order around its edge
+    Polygon { points: Vec<Vec2D> },
+    Circle { center: Vec2D, radius: f32 },
+}
+
+impl Hash for Zone {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Zone::Polygon { points } => points.hash(state),
+            Zone::Circle { center, radius } => {
+                center.hash(state);
+                radius.to_le_bytes().hash(state);
+            }
+        }
+    }
+}
+
+/// Where the pen may draw. A job's include zones can only narrow the machine's, and
+/// exclusion zones from both apply.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Hash)]
+pub struct Masks {
+    // the pen only draws inside these; anywhere on the bed if there are none
+    #[serde(default)]
+    pub include: Vec<Zone>,
+    // the pen never draws inside these, and travel goes around them where it can
+    #[serde(default)]
+    pub exclude: Vec<Zone>,
+    // the machine's include zones once a job has brought its own, which the pen has
+    // to keep inside as well
+    #[serde(skip)]
+    pub within: Vec<Zone>,
+}
+
+/// Merges overlapping intervals, sorting them as it goes
+pub fn union(mut intervals: Intervals) -> Intervals {
+    intervals.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Intervals = Vec::new();
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// The parts of `intervals` outside all of `removed`
+pub fn subtract(intervals: Intervals, removed: &Intervals) -> Intervals {
+    let mut remaining = intervals;
+    for (cut_start, cut_end) in removed {
+        remaining = remaining
+            .into_iter()
+            .flat_map(|(start, end)| {
+                [(start, end.min(*cut_start)), (start.max(*cut_end), end)]
+                    .into_iter()
+                    .filter(|(start, end)| end > start)
+            })
+            .collect();
+    }
+    remaining
+}
+
+/// Keeps only the parts of `intervals` inside `start`..`end`
+pub fn intersect(intervals: Intervals, start: f32, end: f32) -> Intervals {
+    intervals
+        .into_iter()
+        .map(|(a, b)| (a.max(start), b.min(end)))
+        .filter(|(a, b)| b > a)
+        .collect()
+}
+
+impl Zone {
+    pub fn contains(&self, point: Vec2D) -> bool {
+        match self {
+            Zone::Circle { center, radius } => center.distance(point) <= *radius,
+            // even-odd rule
+            Zone::Polygon { points } => {
+                let mut inside = false;
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+                    if (a.y > point.y) != (b.y > point.y)
+                        && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    /// The parts of `from`-`to` inside the zone
+    pub fn inside(&self, from: Vec2D, to: Vec2D) -> Intervals {
+        let delta = to - from;
+        match self {
+            Zone::Circle { center, radius } => {
+                let offset = from - *center;
+                let a = delta.dot(delta);
+                let b = 2.0 * offset.dot(delta);
+                let c = offset.dot(offset) - radius * radius;
+                if a <= f32::EPSILON {
+                    return if c <= 0.0 { vec![(0.0, 1.0)] } else { vec![] };
+                }
+                let discriminant = b * b - 4.0 * a * c;
+                if discriminant < 0.0 {
+                    return vec![];
+                }
+                let root = discriminant.sqrt();
+                intersect(
+                    vec![((-b - root) / (2.0 * a), (-b + root) / (2.0 * a))],
+                    0.0,
+                    1.0,
+                )
+            }
+            Zone::Polygon { points } => {
+                // cut the segment wherever it crosses an edge, then check each piece
+                let mut cuts: Vec<f32> = vec![0.0, 1.0];
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+                    let edge = b - *a;
+                    let denominator = delta.cross(edge);
+                    if denominator.abs() <= f32::EPSILON {
+                        continue;
+                    }
+                    let offset = *a - from;
+                    let t = offset.cross(edge) / denominator;
+                    let u = offset.cross(delta) / denominator;
+                    if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+                        cuts.push(t);
+                    }
+                }
+                cuts.sort_by(|a, b| a.total_cmp(b));
+                union(
+                    cuts.windows(2)
+                        .filter(|pair| {
+                            pair[1] > pair[0]
+                                && self.contains(from.lerp(to, (pair[0] + pair[1]) / 2.0))
+                        })
+                        .map(|pair| (pair[0], pair[1]))
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    /// The zone's edge as a closed loop of points, with circles approximated
+    pub fn outline(&self) -> Vec<Vec2D> {
+        match self {
+            Zone::Polygon { points } => points.clone(),
+            Zone::Circle { center, radius } => (0..CIRCLE_SIDES)
+                .map(|side| {
+                    let theta = side as f32 / CIRCLE_SIDES as f32 * 2.0 * PI;
+                    Vec2D {
+                        x: center.x + radius * theta.cos(),
+                        y: center.y + radius * theta.sin(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // corners for travel to go around, a little way out from the edge
+    fn waypoints(&self) -> Vec<Vec2D> {
+        match self {
+            Zone::Polygon { points } => {
+                let count = points.len().max(1) as f32;
+                let centroid = Vec2D {
+                    x: points.iter().map(|p| p.x).sum::<f32>() / count,
+                    y: points.iter().map(|p| p.y).sum::<f32>() / count,
+                };
+                points
+                    .iter()
+                    .map(|point| {
+                        let length = centroid.distance(*point).max(f32::EPSILON);
+                        centroid.lerp(*point, 1.0 + ROUTE_MARGIN / length)
+                    })
+                    .collect()
+            }
+            // far enough out that the edges between corners clear the circle too
+            Zone::Circle { center, radius } => Zone::Circle {
+                center: *center,
+                radius: (radius + ROUTE_MARGIN) / (PI / CIRCLE_SIDES as f32).cos(),
+            }
+            .outline(),
+        }
+    }
+}
+
+impl Masks {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && self.within.is_empty()
+    }
+
+    /// The machine's masks with a job's applied on top
+    pub fn with_job(&self, job: &Masks) -> Masks {
+        if job.include.is_empty() {
+            return Masks {
+                include: self.include.clone(),
+                exclude: [self.exclude.clone(), job.exclude.clone()].concat(),
+                within: self.within.clone(),
+            };
+        }
+        Masks {
+            include: job.include.clone(),
+            exclude: [self.exclude.clone(), job.exclude.clone()].concat(),
+            within: [self.within.clone(), self.include.clone()].concat(),
+        }
+    }
+
+    pub fn allows(&self, point: Vec2D) -> bool {
+        let inside = |zones: &[Zone]| zones.is_empty() || zones.iter().any(|z| z.contains(point));
+        inside(&self.include)
+            && inside(&self.within)
+            && !self.exclude.iter().any(|zone| zone.contains(point))
+    }
+
+    /// The parts of `from`-`to` the pen may draw
+    pub fn drawable(&self, from: Vec2D, to: Vec2D) -> Intervals {
+        let inside = |zones: &[Zone]| {
+            if zones.is_empty() {
+                vec![(0.0, 1.0)]
+            } else {
+                union(
+                    zones
+                        .iter()
+                        .flat_map(|zone| zone.inside(from, to))
+                        .collect(),
+                )
+            }
+        };
+        // inside both is whatever of one isn't outside the other
+        let outside_machine = subtract(vec![(0.0, 1.0)], &inside(&self.within));
+        let allowed = subtract(inside(&self.include), &outside_machine);
+        let excluded = union(
+            self.exclude
+                .iter()
+                .flat_map(|zone| zone.inside(from, to))
+                .collect(),
+        );
+        subtract(allowed, &excluded)
+    }
+
+    // whether travelling straight from `from` to `to` stays out of every exclusion zone
+    fn clear(&self, from: Vec2D, to: Vec2D) -> bool {
+        let length = from.distance(to);
+        self.exclude.iter().all(|zone| {
+            zone.inside(from, to)
+                .iter()
+                .all(|(start, end)| (end - start) * length <= GRAZE)
+        })
+    }
+
+    // Shortest way from `from` to `to` through the waypoints, not counting `from`.
+    // None if there isn't one.
+    fn route(&self, from: Vec2D, to: Vec2D, dimensions: Vec2D) -> Option<Vec<Vec2D>> {
+        let on_bed =
+            |p: &Vec2D| p.x >= 0.0 && p.y >= 0.0 && p.x <= dimensions.x && p.y <= dimensions.y;
+        let mut nodes: Vec<Vec2D> = vec![from, to];
+        nodes.extend(
+            self.exclude
+                .iter()
+                .flat_map(Zone::waypoints)
+                .filter(|point| on_bed(point) && !self.exclude.iter().any(|z| z.contains(*point))),
+        );
+
+        // Dijkstra; there are only ever a handful of nodes, so no heap
+        let mut best: Vec<f32> = vec![f32::INFINITY; nodes.len()];
+        let mut previous: Vec<Option<usize>> = vec![None; nodes.len()];
+        let mut done: Vec<bool> = vec![false; nodes.len()];
+        best[0] = 0.0;
+        while let Some(current) = (0..nodes.len())
+            .filter(|i| !done[*i] && best[*i].is_finite())
+            .min_by(|a, b| best[*a].total_cmp(&best[*b]))
+        {
+            if current == 1 {
+                break;
+            }
+            done[current] = true;
+            for next in 0..nodes.len() {
+                if done[next] || !self.clear(nodes[current], nodes[next]) {
+                    continue;
+                }
+                let cost = best[current] + nodes[current].distance(nodes[next]);
+                if cost < best[next] {
+                    best[next] = cost;
+                    previous[next] = Some(current);
+                }
+            }
+        }
+
+        let mut path: Vec<Vec2D> = Vec::new();
+        let mut current = 1;
+        while current != 0 {
+            path.push(nodes[current]);
+            current = previous[current]?;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Sends pen-up travel around exclusion zones where there's a way round on the bed,
+    /// and straight across where there isn't. Movements must be absolute and start from
+    /// the origin.
+    pub fn route_travel(&self, movements: &[Movement], dimensions: Vec2D) -> Vec<Movement> {
+        let mut routed: Vec<Movement> = Vec::with_capacity(movements.len());
+        let mut position = Vec2D::default();
+
+        for mv in movements {
+            if !mv.pen_down && !self.clear(position, mv.dest) {
+                if let Some(path) = self.route(position, mv.dest, dimensions) {
+                    routed.extend(path.into_iter().map(|point| Movement {
+                        dest: point,
+                        ..mv.clone()
+                    }));
+                    position = mv.dest;
+                    continue;
+                }
+            }
+            routed.push(mv.clone());
+            position = mv.dest;
+        }
+
+        routed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(size: f32) -> Zone {
+        Zone::Polygon {
+            points: vec![
+                Vec2D { x: 0.0, y: 0.0 },
+                Vec2D { x: size, y: 0.0 },
+                Vec2D { x: size, y: size },
+                Vec2D { x: 0.0, y: size },
+            ],
+        }
+    }
+
+    #[test]
+    fn job_includes_cant_widen_the_machines() {
+        let machine = Masks {
+            include: vec![square(10.0)],
+            ..Masks::default()
+        };
+        let job = Masks {
+            include: vec![square(100.0)],
+            ..Masks::default()
+        };
+        let masks = machine.with_job(&job);
+
+        assert!(masks.allows(Vec2D { x: 5.0, y: 5.0 }));
+        assert!(!masks.allows(Vec2D { x: 50.0, y: 50.0 }));
+
+        let drawable = masks.drawable(Vec2D { x: 0.0, y: 5.0 }, Vec2D { x: 50.0, y: 5.0 });
+        assert_eq!(drawable.len(), 1);
+        assert!((drawable[0].1 - 0.2).abs() < 1e-4);
+    }
+}
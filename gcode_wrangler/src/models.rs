@@ -1,10 +1,17 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use std::collections::hash_map::HashMap;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
-use crate::Flavor;
+use crate::dialect::{Dialect, DialectRegistry};
+use crate::fill::Hatch;
+use crate::masks::Masks;
+use crate::optimize::{MergeReport, SimplifyReport, TravelReport};
+use crate::sanitize::DEFAULT_ALLOWLIST;
+use crate::transform::Transform;
+use crate::{ClipReport, Units};
 
-#[derive(Clone, Deserialize, Default, Serialize, Copy, Debug)]
+#[derive(Clone, Deserialize, Default, Serialize, Copy, Debug, PartialEq)]
 pub struct Vec2D {
     pub x: f32,
     pub y: f32,
@@ -17,49 +24,301 @@ impl Hash for Vec2D {
     }
 }
 
-#[derive(Deserialize, Hash, Default)]
+impl Vec2D {
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn distance(self, other: Vec2D) -> f32 {
+        (other - self).length()
+    }
+
+    pub fn dot(self, other: Vec2D) -> f32 {
+        self.x * other.x + self.y * other.y
+    }
+
+    /// The z component of the 3D cross product; positive when `other` is
+    /// counter-clockwise of `self`
+    pub fn cross(self, other: Vec2D) -> f32 {
+        self.x * other.y - self.y * other.x
+    }
+
+    /// The point `t` of the way from `self` to `other`
+    pub fn lerp(self, other: Vec2D, t: f32) -> Vec2D {
+        Vec2D {
+            x: self.x + (other.x - self.x) * t,
+            y: self.y + (other.y - self.y) * t,
+        }
+    }
+}
+
+/// Turns a movement into an arc around `center`. The center follows the same position
+/// mode as `dest`, so in relative mode it's an offset from where the arc starts.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Hash)]
+pub struct ArcDescription {
+    pub clockwise: bool,
+    pub center: Vec2D,
+}
+
+#[derive(Clone, Debug, Deserialize, Hash, Default)]
 pub struct Movement {
     pub dest: Vec2D,
     pub pen_down: bool,
+    // straight line if absent
+    #[serde(default)]
+    pub arc: Option<ArcDescription>,
+    // the colour this should be drawn in; a change of pen stops for the operator to
+    // swap it, and absent means whatever's already loaded
+    #[serde(default)]
+    pub pen: Option<String>,
+}
+
+/// What the numbers in a submitted job are measured in
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum InputUnits {
+    #[default]
+    #[serde(rename = "mm")]
+    Millimeters,
+    #[serde(rename = "in")]
+    Inches,
+    // at the job's DPI
+    #[serde(rename = "px")]
+    Pixels,
+    // 0 to 1 across the bed in each direction
+    #[serde(rename = "normalized")]
+    Normalized,
+}
+
+impl InputUnits {
+    /// Millimetres per unit along each axis
+    pub fn scale(&self, dpi: f32, dimensions: Vec2D) -> Vec2D {
+        match self {
+            InputUnits::Millimeters => Vec2D { x: 1.0, y: 1.0 },
+            InputUnits::Inches => Vec2D { x: 25.4, y: 25.4 },
+            InputUnits::Pixels => Vec2D {
+                x: 25.4 / dpi,
+                y: 25.4 / dpi,
+            },
+            InputUnits::Normalized => dimensions,
+        }
+    }
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Part of a job drawn with one pen, at its own speeds
+#[derive(Clone, Debug, Deserialize, Hash)]
+pub struct Layer {
+    pub name: String,
+    #[serde(default)]
+    pub movements: Vec<Movement>,
+    // closed polygons to fill, in the same coordinates as the movements; they aren't
+    // outlined, so send the outline as movements too if it should be drawn
+    #[serde(default)]
+    pub shapes: Vec<Vec<Vec2D>>,
+    // fills the shapes, or if there are none, any strokes in the movements that close
+    // on themselves
+    #[serde(default)]
+    pub fill: Option<Hatch>,
+    // a pen from the machine config; whichever is loaded if absent, which to begin with
+    // is the job's
+    #[serde(default)]
+    pub pen: Option<String>,
+    #[serde(default)]
+    pub draw_speed: Option<u32>,
+    #[serde(default)]
+    pub travel_speed: Option<u32>,
+    // how many times to draw it, ie to darken a thin pen
+    #[serde(default = "one")]
+    pub repeat: u32,
+}
+
+impl Layer {
+    /// A layer drawn once with the job's pen and speeds
+    pub fn new(name: &str, movements: Vec<Movement>) -> Self {
+        Layer {
+            name: name.to_string(),
+            movements,
+            shapes: Vec::new(),
+            fill: None,
+            pen: None,
+            draw_speed: None,
+            travel_speed: None,
+            repeat: 1,
+        }
+    }
+}
+
+/// What a client can submit as a job: either a plain list of movements, or layers
+#[derive(Clone, Debug, Deserialize, Hash)]
+#[serde(untagged)]
+pub enum JobPayload {
+    Movements(Vec<Movement>),
+    Layered {
+        layers: Vec<Layer>,
+        #[serde(default)]
+        masks: Masks,
+    },
+}
+
+impl JobPayload {
+    /// The job's layers, and any masks of its own
+    pub fn into_parts(self) -> (Vec<Layer>, Masks) {
+        match self {
+            JobPayload::Movements(movements) => {
+                (vec![Layer::new("default", movements)], Masks::default())
+            }
+            JobPayload::Layered { layers, masks } => (layers, masks),
+        }
+    }
+}
+
+/// What the optional passes over a job did to it
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct JobReport {
+    pub clip: ClipReport,
+    pub simplify: Option<SimplifyReport>,
+    pub travel: Option<TravelReport>,
+    pub merge: Option<MergeReport>,
+    // from the job's coordinates to the bed's, before anything else
+    pub transform: Option<Transform>,
+}
+
+/// Where a job came from, for the header of a saved program
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct JobInfo {
+    pub title: Option<String>,
+    pub user: Option<String>,
+    pub handle: Option<String>,
+    // the pen to load before starting
+    pub pen: Option<String>,
+}
+
+/// How the pen gets lifted and lowered
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Actuator {
+    // a hobby servo on the spindle PWM output, driven with `M3 S<value>`
+    Spindle,
+    // the pen rides on the Z axis; up/down are absolute heights
+    ZAxis,
+    // a pair of commands sent verbatim, ie a fan output switching a solenoid
+    Custom { up: String, down: String },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PenProfile {
+    pub name: String,
+    pub actuator: Actuator,
+    pub up: f32,
+    pub down: f32,
+    // how long to wait for the pen to stop bouncing after it moves
+    #[serde(default)]
+    pub settle_ms: u32,
+    // only meaningful for the Z axis; without one the pen moves at rapid speed
+    #[serde(default)]
+    pub lift_speed: Option<u32>,
+    // any CSS colour; imported drawings use this pen for lines that colour
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+/// Speeds in mm/min
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Feedrates {
+    pub draw: u32,
+    pub travel: u32,
+    // machines that rapid too violently can travel with G1 at the travel speed instead
+    pub rapid_travel: bool,
+}
+
+impl Feedrates {
+    pub fn with_overrides(
+        &self,
+        draw: Option<u32>,
+        travel: Option<u32>,
+        rapid_travel: Option<bool>,
+    ) -> Feedrates {
+        Feedrates {
+            draw: draw.unwrap_or(self.draw),
+            travel: travel.unwrap_or(self.travel),
+            rapid_travel: rapid_travel.unwrap_or(self.rapid_travel),
+        }
+    }
 }
 
 #[derive(Clone, Serialize)]
 pub struct MachineDetails {
     pub dimensions: Vec2D,
-    pub flavor: Flavor,
+    #[serde(serialize_with = "serialize_dialect")]
+    pub flavor: Arc<dyn Dialect>,
     pub device: String,
     pub port: String,
     pub baud_rate: u32,
+    pub feedrates: Feedrates,
+    // decimal places for coordinates in generated programs
+    pub precision: usize,
+    // the first pen is the one jobs get unless they ask for another
+    pub pens: Vec<PenProfile>,
+    // how far, in mm, a run of segments may stray from a circle and still be sent as
+    // one arc; no fitting unless it's set
+    pub arc_tolerance: Option<f32>,
+    // areas every job on this machine must keep inside or out of
+    pub masks: Masks,
+    // what the controller expects programs in
+    pub units: Units,
+    // commands an uploaded program may use, besides the pens' own
+    pub gcode_allowlist: Vec<String>,
 }
 
-impl From<HashMap<String, String>> for MachineDetails {
-    fn from(fromval: HashMap<String, String>) -> Self {
+fn serialize_dialect<S: Serializer>(
+    dialect: &Arc<dyn Dialect>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(dialect.name())
+}
+
+impl MachineDetails {
+    /// Looks up a pen by name, or the default pen if no name is given
+    pub fn pen(&self, name: Option<&str>) -> Option<&PenProfile> {
+        match name {
+            Some(name) => self.pens.iter().find(|pen| pen.name == name),
+            None => self.pens.first(),
+        }
+    }
+
+    /// Like `From<HashMap>`, but with dialects beyond the built-in ones
+    pub fn from_settings(fromval: HashMap<String, String>, dialects: &DialectRegistry) -> Self {
+        let flavor: Arc<dyn Dialect> = {
+            if let Some(name) = fromval.get("flavor") {
+                match dialects.build(name, &fromval) {
+                    Some(dialect) => dialect,
+                    None => {
+                        panic!("Unknown gcode flavor: {name}")
+                    }
+                }
+            } else {
+                panic!("Missing config value: flavor")
+            }
+        };
+
         MachineDetails {
             dimensions: Vec2D {
                 x: fromval
                     .get("xdim")
                     .expect("Missing config value: xdim")
                     .parse()
-                    .unwrap(),
+                    .expect("Invalid config value: xdim"),
                 y: fromval
                     .get("ydim")
                     .expect("Missing config value: ydim")
                     .parse()
-                    .unwrap(),
-            },
-            flavor: {
-                if let Some(maybe_match) = fromval.get("flavor") {
-                    match maybe_match.as_str() {
-                        "GRBL" => Flavor::GRBL,
-                        "Marlin" => Flavor::Marlin,
-                        unknown => {
-                            panic!("Unknown gcode flavor: {unknown}")
-                        }
-                    }
-                } else {
-                    panic!("Missing config value: flavor")
-                }
+                    .expect("Invalid config value: ydim"),
             },
+            pens: vec![flavor.default_pen()],
+            flavor,
             device: fromval
                 .get("name")
                 .expect("Missing config value: name")
@@ -70,9 +329,49 @@ impl From<HashMap<String, String>> for MachineDetails {
                 .to_owned(),
             baud_rate: fromval
                 .get("baud_rate")
-                .expect("Missing config calue: baud_rate")
+                .expect("Missing config value: baud_rate")
                 .parse()
-                .unwrap(),
+                .expect("Invalid config value: baud_rate"),
+            masks: Masks::default(),
+            gcode_allowlist: match fromval.get("gcode_allowlist") {
+                Some(list) => list
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|word| !word.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                None => DEFAULT_ALLOWLIST.map(str::to_string).to_vec(),
+            },
+            units: match fromval.get("units").map(String::as_str) {
+                None | Some("mm") => Units::Millimeters,
+                Some("in") => Units::Inches,
+                Some(other) => panic!("Unknown units: {other}"),
+            },
+            arc_tolerance: fromval.get("arc_tolerance").map(|tolerance| {
+                tolerance
+                    .parse()
+                    .expect("Invalid config value: arc_tolerance")
+            }),
+            precision: fromval.get("precision").map_or(3, |precision| {
+                precision.parse().expect("Invalid config value: precision")
+            }),
+            feedrates: Feedrates {
+                draw: fromval.get("draw_speed").map_or(1000, |speed| {
+                    speed.parse().expect("Invalid config value: draw_speed")
+                }),
+                travel: fromval.get("travel_speed").map_or(3000, |speed| {
+                    speed.parse().expect("Invalid config value: travel_speed")
+                }),
+                rapid_travel: fromval
+                    .get("rapid_travel")
+                    .map(|rapid| rapid.parse().expect("Invalid config value: rapid_travel"))
+                    .unwrap_or(true),
+            },
         }
     }
 }
+
+impl From<HashMap<String, String>> for MachineDetails {
+    fn from(fromval: HashMap<String, String>) -> Self {
+        MachineDetails::from_settings(fromval, &DialectRegistry::default())
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::models::{ArcDescription, Movement, Vec2D};
+
+// 2-opt is quadratic per pass; past this it stops finding enough to be worth the wait
+const MAX_TWO_OPT_PASSES: usize = 16;
+
+// how many strokes, or drawn segments, the greedy searches look through at a time, so
+// a big job takes time in proportion to its size rather than its size squared
+const SEARCH_WINDOW: usize = 1000;
+
+// how far apart, in strokes, the ends of a run 2-opt reverses can be
+const TWO_OPT_WINDOW: usize = 50;
+
+/// Pen-up distance before and after a pass, in mm
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct TravelReport {
+    pub before: f32,
+    pub after: f32,
+}
+
+/// What merging did to a job
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct MergeReport {
+    pub pen_lifts_before: usize,
+    pub pen_lifts_after: usize,
+    // total length, in mm, of segments that retraced lines already drawn
+    pub overdraw_removed: f32,
+}
+
+/// Point counts either side of simplification
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct SimplifyReport {
+    pub points_before: usize,
+    pub points_after: usize,
+}
+
+/// One unbroken pen-down run
+#[derive(Clone, Debug)]
+struct Stroke {
+    start: Vec2D,
+    movements: Vec<Movement>,
+}
+
+impl Stroke {
+    fn end(&self) -> Vec2D {
+        self.movements.last().map_or(self.start, |mv| mv.dest)
+    }
+
+    // each segment's arc belongs to the segment, so it moves along with it and turns
+    // the other way
+    fn reverse(&mut self) {
+        let mut points: Vec<Vec2D> = vec![self.start];
+        points.extend(self.movements.iter().map(|mv| mv.dest));
+        let reversed: Vec<Movement> = self
+            .movements
+            .iter()
+            .zip(points.iter())
+            .rev()
+            .map(|(mv, from)| Movement {
+                dest: *from,
+                arc: mv.arc.map(|arc| ArcDescription {
+                    clockwise: !arc.clockwise,
+                    ..arc
+                }),
+                ..mv.clone()
+            })
+            .collect();
+        self.start = self.end();
+        self.movements = reversed;
+    }
+}
+
+// Splits absolute movements into strokes, plus wherever the pen ends up afterwards if
+// the job finishes with travel
+fn strokes(movements: &[Movement]) -> (Vec<Stroke>, Option<Movement>) {
+    let mut strokes: Vec<Stroke> = Vec::new();
+    let mut position = Vec2D::default();
+    let mut drawing = false;
+    let mut last_travel: Option<Movement> = None;
+
+    for mv in movements {
+        if mv.pen_down {
+            if !drawing {
+                strokes.push(Stroke {
+                    start: position,
+                    movements: Vec::new(),
+                });
+            }
+            if let Some(stroke) = strokes.last_mut() {
+                stroke.movements.push(mv.clone());
+            }
+            last_travel = None;
+        } else {
+            last_travel = Some(Movement {
+                arc: None,
+                ..mv.clone()
+            });
+        }
+        drawing = mv.pen_down;
+        position = mv.dest;
+    }
+
+    (strokes, last_travel)
+}
+
+// The reverse of `strokes`: travel to each stroke and draw it
+fn join(strokes: Vec<Stroke>, last_travel: Option<Movement>) -> Vec<Movement> {
+    let mut movements: Vec<Movement> = Vec::new();
+    for stroke in strokes {
+        movements.push(Movement {
+            dest: stroke.start,
+            pen_down: false,
+            arc: None,
+            pen: stroke.movements[0].pen.clone(),
+        });
+        movements.extend(stroke.movements);
+    }
+    movements.extend(last_travel);
+    movements
+}
+
+/// Total pen-up distance of absolute movements starting from the origin
+pub fn travel_distance(movements: &[Movement]) -> f32 {
+    let mut position = Vec2D::default();
+    let mut total = 0.0;
+    for mv in movements {
+        if !mv.pen_down {
+            total += position.distance(mv.dest);
+        }
+        position = mv.dest;
+    }
+    total
+}
+
+// Only the next `SEARCH_WINDOW` strokes in their original order are candidates, which
+// costs little since drawings tend to be sent roughly in order anyway
+fn nearest_neighbour(strokes: Vec<Stroke>, mut position: Vec2D) -> Vec<Stroke> {
+    let mut ordered: Vec<Stroke> = Vec::with_capacity(strokes.len());
+    let mut remaining: VecDeque<Stroke> = strokes.into();
+    while !remaining.is_empty() {
+        let mut best = (0, false, f32::INFINITY);
+        for (index, stroke) in remaining.iter().take(SEARCH_WINDOW).enumerate() {
+            for (reversed, point) in [(false, stroke.start), (true, stroke.end())] {
+                let d = position.distance(point);
+                if d < best.2 {
+                    best = (index, reversed, d);
+                }
+            }
+        }
+        let mut stroke = match remaining.remove(best.0) {
+            Some(stroke) => stroke,
+            None => break,
+        };
+        if best.1 {
+            stroke.reverse();
+        }
+        position = stroke.end();
+        ordered.push(stroke);
+    }
+    ordered
+}
+
+// Reversing a run of strokes also flips each one, so only the travel into and out of
+// the run changes
+fn two_opt(strokes: &mut [Stroke], origin: Vec2D) {
+    let count = strokes.len();
+    for _ in 0..MAX_TWO_OPT_PASSES {
+        let mut improved = false;
+        for i in 0..count {
+            let before = if i == 0 { origin } else { strokes[i - 1].end() };
+            for j in i + 1..count.min(i + 1 + TWO_OPT_WINDOW) {
+                let first = strokes[i].start;
+                let last = strokes[j].end();
+                let after = strokes.get(j + 1).map(|stroke| stroke.start);
+
+                let current = before.distance(first) + after.map_or(0.0, |a| last.distance(a));
+                let swapped = before.distance(last) + after.map_or(0.0, |a| first.distance(a));
+                if swapped + 1e-4 < current {
+                    strokes[i..=j].reverse();
+                    for stroke in strokes[i..=j].iter_mut() {
+                        stroke.reverse();
+                    }
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+}
+
+/// Reorders and flips pen-down strokes to cut down on pen-up travel. Movements must
+/// be absolute and start from the origin. Strokes only move among neighbours with the
+/// same pen, so tool changes stay where they were. Big jobs are searched a window at a
+/// time, trading a little travel for time that grows linearly.
+pub fn reorder(movements: &[Movement]) -> (Vec<Movement>, TravelReport) {
+    let (strokes, last_travel) = strokes(movements);
+
+    let mut ordered: Vec<Stroke> = Vec::with_capacity(strokes.len());
+    let mut remaining = strokes.into_iter().peekable();
+    while let Some(first) = remaining.next() {
+        let pen = first.movements[0].pen.clone();
+        let mut group = vec![first];
+        while let Some(next) = remaining.next_if(|stroke| stroke.movements[0].pen == pen) {
+            group.push(next);
+        }
+
+        let position = ordered.last().map_or(Vec2D::default(), Stroke::end);
+        let mut group = nearest_neighbour(group, position);
+        two_opt(&mut group, position);
+        ordered.extend(group);
+    }
+
+    let reordered = join(ordered, last_travel);
+
+    let report = TravelReport {
+        before: travel_distance(movements),
+        after: travel_distance(&reordered),
+    };
+    (reordered, report)
+}
+
+// The parts of `from`-`to`, as fractions along it, that don't lie on top of any of the
+// `drawn` segments
+fn undrawn(from: Vec2D, to: Vec2D, drawn: &[(Vec2D, Vec2D)], tolerance: f32) -> Vec<(f32, f32)> {
+    let length = from.distance(to);
+    if length <= f32::EPSILON {
+        return vec![(0.0, 1.0)];
+    }
+    let along = Vec2D {
+        x: (to.x - from.x) / length,
+        y: (to.y - from.y) / length,
+    };
+    let across = Vec2D {
+        x: -along.y,
+        y: along.x,
+    };
+
+    let mut covered: Vec<(f32, f32)> = drawn
+        .iter()
+        .filter(|(a, b)| {
+            (*a - from).dot(across).abs() <= tolerance && (*b - from).dot(across).abs() <= tolerance
+        })
+        .map(|(a, b)| {
+            let (ta, tb) = (
+                (*a - from).dot(along) / length,
+                (*b - from).dot(along) / length,
+            );
+            (ta.min(tb), ta.max(tb))
+        })
+        .filter(|(start, end)| *end > 0.0 && *start < 1.0)
+        .collect();
+    covered.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // anything shorter than the tolerance isn't worth lowering the pen for
+    let slack = tolerance / length;
+    let mut pieces: Vec<(f32, f32)> = Vec::new();
+    let mut cursor = 0.0;
+    for (start, end) in covered {
+        if start - cursor > slack {
+            pieces.push((cursor, start));
+        }
+        cursor = f32::max(cursor, end);
+    }
+    if 1.0 - cursor > slack {
+        pieces.push((cursor, 1.0));
+    }
+    pieces
+}
+
+// Drops the parts of straight segments that retrace any of the last `SEARCH_WINDOW`
+// drawn, splitting strokes where a duplicate is cut out of the middle
+fn remove_overdraw(strokes: Vec<Stroke>, tolerance: f32) -> (Vec<Stroke>, f32) {
+    let mut drawn: Vec<(Vec2D, Vec2D)> = Vec::new();
+    let mut kept: Vec<Stroke> = Vec::new();
+    let mut removed = 0.0;
+
+    for stroke in strokes {
+        let mut from = stroke.start;
+        // whether the last kept piece ended where the next segment starts
+        let mut connected = false;
+
+        for mv in stroke.movements {
+            let to = mv.dest;
+            let pieces = match mv.arc {
+                // arcs are rare enough that checking them isn't worth the geometry
+                Some(_) => vec![(0.0, 1.0)],
+                None => undrawn(
+                    from,
+                    to,
+                    &drawn[drawn.len().saturating_sub(SEARCH_WINDOW)..],
+                    tolerance,
+                ),
+            };
+
+            let mut kept_length = 0.0;
+            for (start, end) in pieces.iter().copied() {
+                if !(connected && start == 0.0) {
+                    kept.push(Stroke {
+                        start: from.lerp(to, start),
+                        movements: Vec::new(),
+                    });
+                }
+                if let Some(current) = kept.last_mut() {
+                    current.movements.push(Movement {
+                        dest: from.lerp(to, end),
+                        ..mv.clone()
+                    });
+                }
+                kept_length += end - start;
+            }
+            removed += from.distance(to) * (1.0 - kept_length);
+            connected = pieces.last().is_some_and(|(_, end)| *end == 1.0);
+
+            if mv.arc.is_none() {
+                drawn.push((from, to));
+            }
+            from = to;
+        }
+    }
+
+    (kept, removed)
+}
+
+// Chains strokes together wherever one ends within `tolerance` of where one of the
+// next `SEARCH_WINDOW` starts or ends, flipping it if need be
+fn chain(strokes: Vec<Stroke>, tolerance: f32) -> Vec<Stroke> {
+    let mut remaining: Vec<Option<Stroke>> = strokes.into_iter().map(Some).collect();
+    let mut chained: Vec<Stroke> = Vec::new();
+
+    for index in 0..remaining.len() {
+        let mut current = match remaining[index].take() {
+            Some(stroke) => stroke,
+            None => continue,
+        };
+        let pen = current.movements[0].pen.clone();
+
+        loop {
+            let end = current.end();
+            let window = index + 1..remaining.len().min(index + 1 + SEARCH_WINDOW);
+            let next = remaining[window.clone()].iter().position(|candidate| {
+                candidate.as_ref().is_some_and(|stroke| {
+                    stroke.movements[0].pen == pen
+                        && (end.distance(stroke.start) <= tolerance
+                            || end.distance(stroke.end()) <= tolerance)
+                })
+            });
+            let mut next = match next.and_then(|next| remaining[window.start + next].take()) {
+                Some(stroke) => stroke,
+                None => break,
+            };
+            if end.distance(next.start) > tolerance {
+                next.reverse();
+            }
+            current.movements.extend(next.movements);
+        }
+
+        chained.push(current);
+    }
+
+    chained
+}
+
+/// Removes retraced segments, then joins strokes that meet within `tolerance` so the
+/// pen stays down between them. Movements must be absolute and start from the origin.
+/// Like `reorder`, big jobs are searched a window at a time.
+pub fn merge(movements: &[Movement], tolerance: f32) -> (Vec<Movement>, MergeReport) {
+    let (strokes, last_travel) = strokes(movements);
+    let pen_lifts_before = strokes.len();
+
+    let (strokes, overdraw_removed) = remove_overdraw(strokes, tolerance);
+    let strokes = chain(strokes, tolerance);
+
+    let report = MergeReport {
+        pen_lifts_before,
+        pen_lifts_after: strokes.len(),
+        overdraw_removed,
+    };
+    (join(strokes, last_travel), report)
+}
+
+// How far `point` is from the segment `a`-`b`
+fn segment_distance(point: Vec2D, a: Vec2D, b: Vec2D) -> f32 {
+    let length_squared = (b - a).dot(b - a);
+    if length_squared <= f32::EPSILON {
+        return point.distance(a);
+    }
+    let t = ((point - a).dot(b - a) / length_squared).clamp(0.0, 1.0);
+    point.distance(a.lerp(b, t))
+}
+
+// Ramer-Douglas-Peucker; which of `points` to keep. Uses its own stack since a long
+// enough curve would blow the real one.
+fn douglas_peucker(points: &[Vec2D], tolerance: f32) -> Vec<bool> {
+    let mut keep = vec![false; points.len()];
+    if points.is_empty() {
+        return keep;
+    }
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut pending = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = pending.pop() {
+        let farthest = (first + 1..last)
+            .map(|index| {
+                let d = segment_distance(points[index], points[first], points[last]);
+                (index, d)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((index, d)) = farthest {
+            if d > tolerance {
+                keep[index] = true;
+                pending.push((first, index));
+                pending.push((index, last));
+            }
+        }
+    }
+    keep
+}
+
+/// Drops points from straight pen-down runs that stray less than `tolerance` from
+/// the line the run would follow without them. Travel and arcs are left alone.
+/// Movements must be absolute and start from the origin.
+pub fn simplify(movements: &[Movement], tolerance: f32) -> (Vec<Movement>, SimplifyReport) {
+    let mut simplified: Vec<Movement> = Vec::with_capacity(movements.len());
+    let mut position = Vec2D::default();
+    // where the current run started, and the movements in it
+    let mut run: Option<(Vec2D, Vec<Movement>)> = None;
+
+    let flush = |run: &mut Option<(Vec2D, Vec<Movement>)>, simplified: &mut Vec<Movement>| {
+        if let Some((start, movements)) = run.take() {
+            let mut points = vec![start];
+            points.extend(movements.iter().map(|mv| mv.dest));
+            let keep = douglas_peucker(&points, tolerance);
+            simplified.extend(
+                movements
+                    .into_iter()
+                    .zip(keep.into_iter().skip(1))
+                    .filter_map(|(mv, keep)| keep.then_some(mv)),
+            );
+        }
+    };
+
+    for mv in movements {
+        let continues = mv.pen_down
+            && mv.arc.is_none()
+            && run
+                .as_ref()
+                .is_none_or(|(_, run)| run.last().is_none_or(|last| last.pen == mv.pen));
+        if !continues {
+            flush(&mut run, &mut simplified);
+        }
+        if mv.pen_down && mv.arc.is_none() {
+            run.get_or_insert_with(|| (position, Vec::new()))
+                .1
+                .push(mv.clone());
+        } else {
+            simplified.push(mv.clone());
+        }
+        position = mv.dest;
+    }
+    flush(&mut run, &mut simplified);
+
+    let report = SimplifyReport {
+        points_before: movements.len(),
+        points_after: simplified.len(),
+    };
+    (simplified, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // absolute movements from (x, y, pen down) triples
+    fn moves(points: &[(f32, f32, bool)]) -> Vec<Movement> {
+        points
+            .iter()
+            .map(|&(x, y, pen_down)| Movement {
+                dest: Vec2D { x, y },
+                pen_down,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // every drawn segment, either way round, sorted so they compare regardless of order
+    fn drawn(movements: &[Movement]) -> Vec<[f32; 4]> {
+        let mut position = Vec2D::default();
+        let mut segments: Vec<[f32; 4]> = Vec::new();
+        for mv in movements {
+            if mv.pen_down {
+                let (a, b) = (position, mv.dest);
+                let (a, b) = if (a.x, a.y) <= (b.x, b.y) {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                segments.push([a.x, a.y, b.x, b.y]);
+            }
+            position = mv.dest;
+        }
+        segments.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        segments
+    }
+
+    #[test]
+    fn reorder_keeps_every_stroke_whole() {
+        let movements = moves(&[
+            (50.0, 0.0, false),
+            (60.0, 0.0, true),
+            (0.0, 1.0, false),
+            (10.0, 1.0, true),
+            (10.0, 2.0, true),
+            (40.0, 0.0, false),
+            (30.0, 0.0, true),
+        ]);
+        let (reordered, report) = reorder(&movements);
+
+        assert!(report.after < report.before);
+        assert_eq!(drawn(&reordered), drawn(&movements));
+        // one travel move to each of the three strokes, and the two-segment one unbroken
+        // whichever way round it went
+        assert_eq!(reordered.iter().filter(|mv| !mv.pen_down).count(), 3);
+        let corner = reordered
+            .iter()
+            .position(|mv| mv.dest == Vec2D { x: 10.0, y: 1.0 })
+            .unwrap();
+        assert!(reordered[corner].pen_down && reordered[corner + 1].pen_down);
+        assert!(!reordered[corner - 1].pen_down);
+    }
+
+    #[test]
+    fn merge_joins_touching_strokes_and_drops_retraced_ones() {
+        let movements = moves(&[
+            (10.0, 0.0, true),
+            (10.0, 0.0, false),
+            (10.0, 10.0, true),
+            (0.0, 0.0, false),
+            (5.0, 0.0, true),
+            (30.0, 30.0, false),
+            (40.0, 30.0, true),
+        ]);
+        let (merged, report) = merge(&movements, 0.01);
+
+        assert_eq!(report.pen_lifts_before, 4);
+        // the retrace is gone, the corner is one stroke, and the far line stays apart
+        assert_eq!(report.pen_lifts_after, 2);
+        assert!((report.overdraw_removed - 5.0).abs() < 1e-4);
+        assert_eq!(
+            drawn(&merged),
+            drawn(&moves(&[
+                (10.0, 0.0, true),
+                (10.0, 10.0, true),
+                (30.0, 30.0, false),
+                (40.0, 30.0, true),
+            ]))
+        );
+    }
+
+    #[test]
+    fn simplify_only_touches_pen_down_runs() {
+        let mut movements = moves(&[
+            (5.0, 0.0, true),
+            (10.0, 0.0, true),
+            (10.0, 5.0, false),
+            (10.0, 10.0, false),
+            (15.0, 10.0, true),
+            (20.0, 10.0, true),
+        ]);
+        // a pen change breaks the run, so the point before it stays
+        movements[5].pen = Some("red".to_string());
+        let (simplified, report) = simplify(&movements, 0.1);
+
+        assert_eq!(report.points_before, 6);
+        assert_eq!(report.points_after, 5);
+        let kept: Vec<(f32, f32, bool)> = simplified
+            .iter()
+            .map(|mv| (mv.dest.x, mv.dest.y, mv.pen_down))
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                (10.0, 0.0, true),
+                (10.0, 5.0, false),
+                (10.0, 10.0, false),
+                (15.0, 10.0, true),
+                (20.0, 10.0, true),
+            ]
+        );
+    }
+}
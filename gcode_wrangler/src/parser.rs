@@ -0,0 +1,648 @@
+use std::fmt;
+
+use crate::dialect::{Dialect, LOAD_PEN_COMMENT};
+use crate::models::PenProfile;
+use crate::{ArcCenter, GCode, Position, StepperState, Units, Vec3};
+
+// letters that some flavors write without a value, ie `G28 X Y` or `M18 Z`
+const BARE_LETTERS: [char; 3] = ['X', 'Y', 'Z'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    MissingValue(char),
+    InvalidNumber(String),
+    UnexpectedCharacter(char),
+    UnterminatedComment,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingValue(letter) => write!(f, "{letter} word has no value"),
+            ParseErrorKind::InvalidNumber(number) => write!(f, "invalid number {number:?}"),
+            ParseErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character {c:?}"),
+            ParseErrorKind::UnterminatedComment => write!(f, "comment is never closed"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone)]
+struct Word {
+    letter: char,
+    value: Option<f32>,
+    raw: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    G(f32),
+    M(f32),
+    // GRBL `$` commands and Klipper-style macros, kept as the whole line
+    Extended(String),
+    // `; text` or `(text)`, trimmed
+    Comment(String),
+    Modal,
+}
+
+// One command word plus the parameter words that follow it on the same line
+#[derive(Debug, Clone)]
+struct Statement {
+    command: Command,
+    params: Vec<Word>,
+    text: String,
+    line: usize,
+}
+
+impl Statement {
+    fn param(&self, letter: char) -> Option<f32> {
+        self.params
+            .iter()
+            .find(|word| word.letter == letter)
+            .and_then(|word| word.value)
+    }
+
+    fn only_uses(&self, letters: &[char]) -> bool {
+        self.params
+            .iter()
+            .all(|word| letters.contains(&word.letter))
+    }
+
+    fn has_values(&self) -> bool {
+        self.params.iter().all(|word| word.value.is_some())
+    }
+
+    fn target(&self) -> Vec3 {
+        Vec3 {
+            x: self.param('X'),
+            y: self.param('Y'),
+            z: self.param('Z'),
+        }
+    }
+
+    fn same_as(&self, other: &Statement) -> bool {
+        self.command == other.command
+            && self.params.len() == other.params.len()
+            && self
+                .params
+                .iter()
+                .zip(&other.params)
+                .all(|(a, b)| a.letter == b.letter && a.value == b.value)
+    }
+
+    fn raw(&self) -> GCode {
+        GCode::Raw(self.text.clone())
+    }
+}
+
+/// Parses G-code text into a list of instructions. Anything that's well-formed but
+/// doesn't map onto a `GCode` variant for this dialect comes back as `GCode::Raw`.
+/// Comments are kept, after whatever else is on their line.
+pub fn parse(
+    input: &str,
+    dialect: &dyn Dialect,
+    pen: &PenProfile,
+) -> Result<Vec<GCode>, ParseError> {
+    Ok(parse_lines(input, dialect, pen)?
+        .into_iter()
+        .map(|(_, gcode)| gcode)
+        .collect())
+}
+
+/// Like `parse`, with the line each instruction starts on, counting from 1
+pub fn parse_lines(
+    input: &str,
+    dialect: &dyn Dialect,
+    pen: &PenProfile,
+) -> Result<Vec<(usize, GCode)>, ParseError> {
+    let mut statements: Vec<Statement> = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        statements.extend(tokenize(line, index + 1)?);
+    }
+
+    let mut parsed: Vec<(usize, GCode)> = Vec::new();
+    let mut motion: Option<u32> = None;
+    let mut remaining: &[Statement] = &statements;
+    let idioms = idioms(dialect, pen);
+
+    while !remaining.is_empty() {
+        let (gcode, consumed) = match recognize(dialect, pen, &idioms, remaining) {
+            Some(found) => found,
+            None => (interpret(&remaining[0], &mut motion, dialect), 1),
+        };
+        parsed.push((remaining[0].line, gcode));
+        remaining = &remaining[consumed..];
+    }
+
+    Ok(parsed)
+}
+
+fn is_extended(first: char, second: Option<&char>) -> bool {
+    first == '$'
+        || (first.is_ascii_alphabetic()
+            && second.is_some_and(|c| c.is_ascii_alphabetic() || *c == '_'))
+}
+
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<Statement>, ParseError> {
+    let chars: Vec<char> = line.chars().collect();
+    let error = |column: usize, kind: ParseErrorKind| ParseError {
+        line: line_number,
+        column: column + 1,
+        kind,
+    };
+
+    let mut words: Vec<Word> = Vec::new();
+    // kept so a saved program comes back the same, and put after the line's commands
+    let mut comments: Vec<String> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ';' => {
+                comments.push(chars[i + 1..].iter().collect::<String>().trim().to_string());
+                break;
+            }
+            '*' => break,
+            '(' => match chars[i..].iter().position(|&c| c == ')') {
+                Some(offset) => {
+                    let text: String = chars[i + 1..i + offset].iter().collect();
+                    comments.push(text.trim().to_string());
+                    i += offset + 1;
+                }
+                None => return Err(error(i, ParseErrorKind::UnterminatedComment)),
+            },
+            c if words.is_empty() && is_extended(c, chars.get(i + 1)) => {
+                let rest: String = chars[i..].iter().collect();
+                let (extended, comment) = match rest.split_once(';') {
+                    Some((extended, comment)) => (extended, Some(comment)),
+                    None => (rest.as_str(), None),
+                };
+                let extended = extended.trim();
+                let mut statements = vec![Statement {
+                    command: Command::Extended(extended.to_string()),
+                    params: Vec::new(),
+                    text: extended.to_string(),
+                    line: line_number,
+                }];
+                statements.extend(comment.map(|text| comment_statement(text, line_number)));
+                return Ok(statements);
+            }
+            // tape markers around a program, which controllers ignore
+            '%' => i += 1,
+            c if c.is_whitespace() => i += 1,
+            c if c.is_ascii_alphabetic() => {
+                let letter = c.to_ascii_uppercase();
+                let start = i;
+                i += 1;
+                while i < chars.len()
+                    && (chars[i].is_ascii_digit() || matches!(chars[i], '.' | '-' | '+'))
+                {
+                    i += 1;
+                }
+                let number: String = chars[start + 1..i].iter().collect();
+                let value = if number.is_empty() {
+                    if !BARE_LETTERS.contains(&letter) {
+                        return Err(error(start, ParseErrorKind::MissingValue(letter)));
+                    }
+                    None
+                } else {
+                    match number.parse::<f32>() {
+                        Ok(value) => Some(value),
+                        Err(_) => {
+                            return Err(error(start + 1, ParseErrorKind::InvalidNumber(number)))
+                        }
+                    }
+                };
+                // line numbers only matter to the controller's resend logic
+                if letter != 'N' {
+                    words.push(Word {
+                        letter,
+                        value,
+                        raw: chars[start..i].iter().collect(),
+                    });
+                }
+            }
+            unexpected => return Err(error(i, ParseErrorKind::UnexpectedCharacter(unexpected))),
+        }
+    }
+
+    let mut statements: Vec<Statement> = Vec::new();
+    for word in words {
+        let command = match (word.letter, word.value) {
+            ('G', Some(code)) => Some(Command::G(code)),
+            ('M', Some(code)) => Some(Command::M(code)),
+            _ => None,
+        };
+        match (command, statements.last_mut()) {
+            (Some(command), _) => statements.push(Statement {
+                command,
+                params: Vec::new(),
+                text: word.raw,
+                line: line_number,
+            }),
+            (None, Some(current)) => {
+                current.text = format!("{} {}", current.text, word.raw);
+                current.params.push(word);
+            }
+            (None, None) => statements.push(Statement {
+                command: Command::Modal,
+                text: word.raw.clone(),
+                params: vec![word],
+                line: line_number,
+            }),
+        }
+    }
+    statements.extend(
+        comments
+            .iter()
+            .map(|text| comment_statement(text, line_number)),
+    );
+
+    Ok(statements)
+}
+
+fn comment_statement(text: &str, line_number: usize) -> Statement {
+    Statement {
+        command: Command::Comment(text.trim().to_string()),
+        params: Vec::new(),
+        text: text.trim().to_string(),
+        line: line_number,
+    }
+}
+
+// Instructions whose text is entirely up to the dialect. Rather than teaching the
+// parser every controller's spelling, we render these and look for them in the input.
+fn idioms(dialect: &dyn Dialect, pen: &PenProfile) -> Vec<(GCode, Vec<Statement>)> {
+    let states = [StepperState::Enabled, StepperState::Disabled];
+    let flags = [true, false];
+
+    let mut candidates = vec![GCode::Activate, GCode::Deactivate, GCode::EndProgram];
+    for x in flags {
+        for y in flags {
+            for z in flags {
+                if x || y || z {
+                    candidates.push(GCode::Home { x, y, z });
+                }
+            }
+        }
+    }
+    for x in states {
+        for y in states {
+            for z in states {
+                candidates.push(GCode::StepperControl { x, y, z });
+            }
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let rendered = rendered_statements(dialect, pen, &candidate)?;
+            Some((candidate, rendered))
+        })
+        .collect()
+}
+
+fn rendered_statements(
+    dialect: &dyn Dialect,
+    pen: &PenProfile,
+    gcode: &GCode,
+) -> Option<Vec<Statement>> {
+    let text = dialect.render(gcode, pen).ok()?;
+    let mut statements: Vec<Statement> = Vec::new();
+    for line in text.lines() {
+        statements.extend(tokenize(line, 0).ok()?);
+    }
+    if statements.is_empty() {
+        None
+    } else {
+        Some(statements)
+    }
+}
+
+fn starts_with(statements: &[Statement], prefix: &[Statement]) -> bool {
+    statements.len() >= prefix.len()
+        && statements
+            .iter()
+            .zip(prefix)
+            .all(|(statement, expected)| statement.same_as(expected))
+}
+
+// Picks the longest idiom the input starts with, preferring earlier ones on a tie
+fn recognize(
+    dialect: &dyn Dialect,
+    pen: &PenProfile,
+    idioms: &[(GCode, Vec<Statement>)],
+    statements: &[Statement],
+) -> Option<(GCode, usize)> {
+    // dwells get their own candidates since the value is only known from the input
+    let dwells: Vec<(GCode, Vec<Statement>)> = statements
+        .iter()
+        .take(2)
+        .flat_map(|statement| [statement.param('P'), statement.param('S')])
+        .flatten()
+        .flat_map(|value| [(value * 1000.0).round(), value.round()])
+        .filter_map(|ms| {
+            let pause = GCode::Pause(ms as u32);
+            let rendered = rendered_statements(dialect, pen, &pause)?;
+            Some((pause, rendered))
+        })
+        .collect();
+    // as do tool changes, which name the pen in a comment
+    let tool_changes: Vec<(GCode, Vec<Statement>)> = statements
+        .iter()
+        .take(3)
+        .filter_map(|statement| match &statement.command {
+            Command::Comment(text) => text.strip_prefix(LOAD_PEN_COMMENT),
+            _ => None,
+        })
+        .filter_map(|name| {
+            let change = GCode::ToolChange(name.to_string());
+            let rendered = rendered_statements(dialect, pen, &change)?;
+            Some((change, rendered))
+        })
+        .collect();
+
+    let mut best: Option<(GCode, usize)> = None;
+    for (gcode, rendered) in idioms.iter().chain(&dwells).chain(&tool_changes) {
+        let longer = best.as_ref().is_none_or(|(_, len)| rendered.len() > *len);
+        if longer && starts_with(statements, rendered) {
+            best = Some((gcode.clone(), rendered.len()));
+        }
+    }
+    best
+}
+
+fn interpret(statement: &Statement, motion: &mut Option<u32>, dialect: &dyn Dialect) -> GCode {
+    let code = match &statement.command {
+        Command::G(code) | Command::M(code) if code.fract() == 0.0 => *code as u32,
+        Command::Modal => {
+            return match motion {
+                Some(code) => moving(*code, statement).unwrap_or_else(|| statement.raw()),
+                None => statement.raw(),
+            }
+        }
+        Command::Comment(text) => return GCode::Comment(text.clone()),
+        _ => return statement.raw(),
+    };
+
+    match &statement.command {
+        Command::G(_) => match code {
+            0..=3 => match moving(code, statement) {
+                Some(gcode) => {
+                    *motion = Some(code);
+                    gcode
+                }
+                None => statement.raw(),
+            },
+            4 if statement.params.len() == 1 => {
+                let millis = dialect.capabilities().dwell_in_milliseconds;
+                match (statement.param('P'), statement.param('S')) {
+                    (Some(ms), None) if millis => GCode::Pause(ms.round() as u32),
+                    (Some(seconds), None) | (None, Some(seconds)) => {
+                        GCode::Pause((seconds * 1000.0).round() as u32)
+                    }
+                    _ => statement.raw(),
+                }
+            }
+            17 if statement.params.is_empty() => GCode::SetXY,
+            20 if statement.params.is_empty() => GCode::SetUnits(Units::Inches),
+            21 if statement.params.is_empty() => GCode::SetUnits(Units::Millimeters),
+            // bare G28 homes everything; without per-axis homing it may not home at all,
+            // and with values it goes by way of them first
+            28 if statement.only_uses(&BARE_LETTERS)
+                && statement.params.iter().all(|word| word.value.is_none())
+                && dialect.capabilities().per_axis_homing =>
+            {
+                let axes: Vec<char> = statement.params.iter().map(|w| w.letter).collect();
+                let all = axes.is_empty();
+                GCode::Home {
+                    x: all || axes.contains(&'X'),
+                    y: all || axes.contains(&'Y'),
+                    z: all || axes.contains(&'Z'),
+                }
+            }
+            90 if statement.params.is_empty() => GCode::SetPositionMode(Position::Absolute),
+            91 if statement.params.is_empty() => GCode::SetPositionMode(Position::Relative),
+            92 if statement.only_uses(&BARE_LETTERS) && statement.has_values() => {
+                GCode::SetCurrentPosition(statement.target())
+            }
+            _ => statement.raw(),
+        },
+        _ => statement.raw(),
+    }
+}
+
+// G0 through G3, or None if the words don't make sense for the motion
+fn moving(code: u32, statement: &Statement) -> Option<GCode> {
+    let letters: &[char] = match code {
+        0 | 1 => &['X', 'Y', 'Z', 'F'],
+        _ => &['X', 'Y', 'Z', 'F', 'I', 'J', 'R'],
+    };
+    if !statement.only_uses(letters) || !statement.has_values() {
+        return None;
+    }
+
+    let target = statement.target();
+    let feedrate = statement.param('F').map(|f| f.round() as u32);
+    match code {
+        0 => return Some(GCode::LinearMove { target, feedrate }),
+        1 => return Some(GCode::LinearDraw { target, feedrate }),
+        _ => (),
+    }
+
+    let (i, j, r) = (
+        statement.param('I'),
+        statement.param('J'),
+        statement.param('R'),
+    );
+    let center = match (i, j, r) {
+        // the radius form needs an endpoint to find the center from
+        (None, None, Some(r)) if !target.is_empty() => ArcCenter::Radius(r),
+        (Some(_), _, None) | (_, Some(_), None) => ArcCenter::Offset {
+            i: i.unwrap_or(0.0),
+            j: j.unwrap_or(0.0),
+        },
+        _ => return None,
+    };
+
+    Some(if code == 2 {
+        GCode::ClockwiseArc {
+            target,
+            center,
+            feedrate,
+        }
+    } else {
+        GCode::CounterClockwiseArc {
+            target,
+            center,
+            feedrate,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::{Grbl, Marlin};
+
+    fn xy(x: f32, y: f32) -> Vec3 {
+        Vec3 {
+            x: Some(x),
+            y: Some(y),
+            z: None,
+        }
+    }
+
+    // everything that should come back from its own rendering unchanged
+    fn program(home: GCode, steppers: GCode) -> Vec<GCode> {
+        vec![
+            GCode::Comment("hello".to_string()),
+            GCode::SetUnits(Units::Millimeters),
+            GCode::SetUnits(Units::Inches),
+            GCode::SetPositionMode(Position::Relative),
+            GCode::SetPositionMode(Position::Absolute),
+            GCode::SetXY,
+            home,
+            GCode::SetCurrentPosition(xy(0.0, 0.0)),
+            GCode::Deactivate,
+            GCode::LinearMove {
+                target: xy(10.0, 20.0),
+                feedrate: Some(3000),
+            },
+            GCode::Activate,
+            GCode::LinearDraw {
+                target: xy(1.5, -2.0),
+                feedrate: None,
+            },
+            GCode::ClockwiseArc {
+                target: xy(11.5, -2.0),
+                center: ArcCenter::Offset { i: 5.0, j: 0.0 },
+                feedrate: Some(1000),
+            },
+            GCode::CounterClockwiseArc {
+                target: xy(1.5, -2.0),
+                center: ArcCenter::Radius(-5.0),
+                feedrate: None,
+            },
+            GCode::Pause(250),
+            GCode::Deactivate,
+            GCode::ToolChange("red".to_string()),
+            GCode::Raw("M42 P4 S255".to_string()),
+            steppers,
+            GCode::EndProgram,
+        ]
+    }
+
+    fn round_trip(dialect: &dyn Dialect, gcode: &[GCode]) {
+        let pen = dialect.default_pen();
+        for op in gcode {
+            let text = dialect.render(op, &pen).unwrap();
+            assert_eq!(
+                parse(&text, dialect, &pen).unwrap(),
+                vec![op.clone()],
+                "{} rendered {op:?} as {text:?}",
+                dialect.name()
+            );
+        }
+
+        let text = dialect.render_all(gcode, &pen).unwrap().join("\n");
+        assert_eq!(parse(&text, dialect, &pen).unwrap(), gcode);
+    }
+
+    #[test]
+    fn grbl_round_trips() {
+        let all = |state| GCode::StepperControl {
+            x: state,
+            y: state,
+            z: state,
+        };
+        round_trip(
+            &Grbl,
+            &program(
+                GCode::Home {
+                    x: true,
+                    y: true,
+                    z: true,
+                },
+                all(StepperState::Disabled),
+            ),
+        );
+    }
+
+    #[test]
+    fn marlin_round_trips() {
+        let marlin = Marlin {
+            pen_up: 5.0,
+            pen_down: 0.0,
+            filament_change: false,
+        };
+        round_trip(
+            &marlin,
+            &program(
+                GCode::Home {
+                    x: true,
+                    y: true,
+                    z: false,
+                },
+                GCode::StepperControl {
+                    x: StepperState::Disabled,
+                    y: StepperState::Disabled,
+                    z: StepperState::Enabled,
+                },
+            ),
+        );
+    }
+
+    #[test]
+    fn comments_and_tape_markers() {
+        let pen = Grbl.default_pen();
+        let parsed = parse(
+            "%\n(header)\nG1 X1 ; draw\n$H ;home\n; done\n%",
+            &Grbl,
+            &pen,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                GCode::Comment("header".to_string()),
+                GCode::LinearDraw {
+                    target: Vec3 {
+                        x: Some(1.0),
+                        y: None,
+                        z: None,
+                    },
+                    feedrate: None,
+                },
+                GCode::Comment("draw".to_string()),
+                GCode::Home {
+                    x: true,
+                    y: true,
+                    z: true,
+                },
+                GCode::Comment("home".to_string()),
+                GCode::Comment("done".to_string()),
+            ]
+        );
+    }
+}
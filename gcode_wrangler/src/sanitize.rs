@@ -0,0 +1,529 @@
+use std::fmt;
+
+use crate::arcs;
+use crate::models::{Actuator, ArcDescription, MachineDetails, Movement, Vec2D};
+use crate::parser::{self, ParseError};
+use crate::{scaled, ArcCenter, GCode, Position, Units, Vec3};
+
+/// What an uploaded program may use on a machine that doesn't say. Pen moves in the
+/// machine's own style are always allowed.
+pub const DEFAULT_ALLOWLIST: [&str; 15] = [
+    "G0", "G1", "G2", "G3", "G4", "G17", "G20", "G21", "G28", "G90", "G91", "G92", "M0", "M2",
+    "M30",
+];
+
+// how far, in mm, a program may stray past the edge of the bed before it's rejected
+const BED_SLACK: f32 = 1e-3;
+
+// go to a position stored in the controller, which could be anywhere
+const STORED_MOVES: [&str; 2] = ["G28", "G30"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    Parse(ParseError),
+    NotAllowed(String),
+    OffBed(Vec2D),
+    // a Z height outside every pen's range
+    OffPen(f32),
+    // goes somewhere, or passes words along, that can't be worked out from the line
+    Unchecked,
+}
+
+/// A line of an uploaded program that can't be sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    // counting from 1
+    pub line: usize,
+    pub text: String,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}: ", self.line, self.text.trim())?;
+        match &self.reason {
+            Reason::Parse(e) => write!(f, "{}", e.kind),
+            Reason::NotAllowed(command) => {
+                write!(f, "{command} isn't allowed on this machine")
+            }
+            Reason::OffBed(point) => {
+                write!(f, "goes off the bed at X{:.3} Y{:.3}", point.x, point.y)
+            }
+            Reason::OffPen(z) => write!(f, "moves Z to {z:.3}, outside the pens' range"),
+            Reason::Unchecked => write!(f, "can't be checked against the machine"),
+        }
+    }
+}
+
+/// An uploaded program that passed every check
+#[derive(Debug, Clone, Default)]
+pub struct Checked {
+    // in millimetres and absolute, without any offset of its own, with the preamble and
+    // footer still to be added
+    pub gcode: Vec<GCode>,
+    // what it draws, for the preview
+    pub movements: Vec<Movement>,
+}
+
+// `G01` and `g1` are both `G1`, while `$H` and the like are left as they are
+fn normalize(word: &str) -> String {
+    let word = word.to_ascii_uppercase();
+    let mut chars = word.chars();
+    match (chars.next(), chars.as_str().parse::<f32>()) {
+        (Some(letter), Ok(number)) if letter.is_ascii_alphabetic() => format!("{letter}{number}"),
+        _ => word,
+    }
+}
+
+fn first_word(text: &str) -> String {
+    normalize(text.split_whitespace().next().unwrap_or_default())
+}
+
+// The command an instruction is sent as, or None for the machine's own pen moves
+fn command(gcode: &GCode, machine: &MachineDetails) -> Option<String> {
+    let name = match gcode {
+        GCode::Activate | GCode::Deactivate | GCode::EndProgram | GCode::Comment(_) => return None,
+        GCode::LinearMove { .. } => "G0",
+        GCode::LinearDraw { .. } => "G1",
+        GCode::ClockwiseArc { .. } => "G2",
+        GCode::CounterClockwiseArc { .. } => "G3",
+        GCode::Pause(_) => "G4",
+        GCode::SetXY => "G17",
+        GCode::SetUnits(Units::Inches) => "G20",
+        GCode::SetUnits(Units::Millimeters) => "G21",
+        GCode::SetPositionMode(Position::Absolute) => "G90",
+        GCode::SetPositionMode(Position::Relative) => "G91",
+        GCode::SetCurrentPosition(_) => "G92",
+        GCode::Raw(text) => return Some(first_word(text)),
+        // spelled however the dialect spells them
+        other => {
+            let pen = machine.pen(None)?;
+            return machine
+                .flavor
+                .render(other, pen)
+                .ok()
+                .map(|text| first_word(&text));
+        }
+    };
+    Some(name.to_string())
+}
+
+// Whether raw text is one of the machine's pens moving, and nothing else, rather than,
+// say, the spindle being turned up: Some(true) for down and Some(false) for up
+fn pen_move(text: &str, machine: &MachineDetails) -> Option<bool> {
+    let text = text.trim();
+    machine.pens.iter().find_map(|pen| match &pen.actuator {
+        Actuator::Spindle => {
+            let words: Vec<String> = text.split_whitespace().map(normalize).collect();
+            let [command, power] = words.as_slice() else {
+                return None;
+            };
+            if command != "M3" {
+                return None;
+            }
+            let power: f32 = power.strip_prefix('S')?.parse().ok()?;
+            if power == pen.down {
+                Some(true)
+            } else if power == pen.up {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        Actuator::Custom { down, .. } if text == down.trim() => Some(true),
+        Actuator::Custom { up, .. } if text == up.trim() => Some(false),
+        _ => None,
+    })
+}
+
+// The lowest and highest Z a Z axis pen goes to, or None if no pen uses Z
+fn pen_range(machine: &MachineDetails) -> Option<(f32, f32)> {
+    machine
+        .pens
+        .iter()
+        .filter(|pen| pen.actuator == Actuator::ZAxis)
+        .map(|pen| (pen.up.min(pen.down), pen.up.max(pen.down)))
+        .reduce(|(low, high), (pen_low, pen_high)| (low.min(pen_low), high.max(pen_high)))
+}
+
+/// Checks an uploaded program against the machine's allowlist, bed and pen heights, one
+/// rejection for every line that fails. Anything passed along as it is has to be a
+/// single allowed word, since its parameters can't be checked. Programs in inches come
+/// back in millimetres, since that's what the rest of the pipeline works in, and any
+/// relative mode or offset the program leaves behind is undone so the footer lands
+/// where it should.
+pub fn check(source: &str, machine: &MachineDetails) -> Result<Checked, Vec<Rejection>> {
+    let lines: Vec<&str> = source.lines().collect();
+    let text = |line: usize| lines.get(line - 1).copied().unwrap_or_default().to_string();
+    let pen = machine
+        .pen(None)
+        .cloned()
+        .unwrap_or_else(|| machine.flavor.default_pen());
+
+    let parsed = parser::parse_lines(source, machine.flavor.as_ref(), &pen).map_err(|e| {
+        vec![Rejection {
+            line: e.line,
+            text: text(e.line),
+            reason: Reason::Parse(e),
+        }]
+    })?;
+
+    let allowed: Vec<String> = machine
+        .gcode_allowlist
+        .iter()
+        .map(|word| normalize(word))
+        .collect();
+    let on_bed = |p: Vec2D| {
+        p.x >= -BED_SLACK
+            && p.y >= -BED_SLACK
+            && p.x <= machine.dimensions.x + BED_SLACK
+            && p.y <= machine.dimensions.y + BED_SLACK
+    };
+
+    let pen_range = pen_range(machine);
+
+    let mut checked = Checked::default();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    // where the machine is, and what the program's coordinates are offset from that by
+    let mut position = Vec2D::default();
+    let mut offset = Vec2D::default();
+    // Z can't be offset, and isn't known until something sets it
+    let mut z: Option<f32> = None;
+    let mut relative = false;
+    let mut inches = false;
+    let mut pen_down = false;
+
+    for (line, op) in parsed {
+        let mut reject = |reason: Reason| {
+            rejections.push(Rejection {
+                line,
+                text: text(line),
+                reason,
+            })
+        };
+
+        let raw_pen = match &op {
+            GCode::Raw(text) => pen_move(text, machine),
+            _ => None,
+        };
+        if raw_pen.is_none() {
+            if let Some(command) = command(&op, machine) {
+                if !allowed.contains(&command) {
+                    reject(Reason::NotAllowed(command));
+                    continue;
+                }
+            }
+            // there's no telling what the parameters of anything the parser didn't
+            // understand would do
+            if let GCode::Raw(text) = &op {
+                let mut words = text.split_whitespace();
+                let command = normalize(words.next().unwrap_or_default());
+                if words.next().is_some() || STORED_MOVES.contains(&command.as_str()) {
+                    reject(Reason::Unchecked);
+                    continue;
+                }
+            }
+        }
+
+        let op = match op {
+            GCode::SetUnits(units) => {
+                inches = units == Units::Inches;
+                GCode::SetUnits(Units::Millimeters)
+            }
+            op if inches => scaled(&op, 25.4),
+            op => op,
+        };
+
+        // the program's coordinates for a target, turned into the machine's
+        let resolve = |target: Vec3| {
+            let current = position - offset;
+            let axis = |value: Option<f32>, current: f32| match value {
+                Some(value) if relative => current + value,
+                Some(value) => value,
+                None => current,
+            };
+            Vec2D {
+                x: axis(target.x, current.x),
+                y: axis(target.y, current.y),
+            } + offset
+        };
+        // and the machine's Z for one, if it's within the pens' reach
+        let height = |target: Vec3| match target.z {
+            None => Ok(z),
+            Some(value) => {
+                let dest = match z {
+                    Some(z) if relative => z + value,
+                    None if relative => return Err(Reason::Unchecked),
+                    _ => value,
+                };
+                match pen_range {
+                    Some((low, high)) if dest >= low - BED_SLACK && dest <= high + BED_SLACK => {
+                        Ok(Some(dest))
+                    }
+                    _ => Err(Reason::OffPen(dest)),
+                }
+            }
+        };
+
+        match &op {
+            GCode::Activate | GCode::Deactivate => {
+                pen_down = op == GCode::Activate;
+                if pen.actuator == Actuator::ZAxis {
+                    z = Some(if pen_down { pen.down } else { pen.up });
+                }
+            }
+            GCode::Raw(_) => pen_down = raw_pen.unwrap_or(pen_down),
+            GCode::SetPositionMode(mode) => relative = *mode == Position::Relative,
+            GCode::SetCurrentPosition(target) => {
+                // pen heights are in the machine's Z, so it can't be moved
+                if target.z.is_some() {
+                    reject(Reason::Unchecked);
+                    continue;
+                }
+                let current = position - offset;
+                let program = Vec2D {
+                    x: target.x.unwrap_or(current.x),
+                    y: target.y.unwrap_or(current.y),
+                };
+                offset = position - program;
+            }
+            GCode::Home { x, y, z: homed_z } => {
+                if *x {
+                    position.x = 0.0;
+                }
+                if *y {
+                    position.y = 0.0;
+                }
+                // Z homes to one end or the other, depending on the machine
+                if *homed_z {
+                    z = None;
+                }
+            }
+            GCode::LinearMove { target, .. } | GCode::LinearDraw { target, .. } => {
+                let dest = resolve(*target);
+                if !on_bed(dest) {
+                    reject(Reason::OffBed(dest));
+                    continue;
+                }
+                let new_z = match height(*target) {
+                    Ok(new_z) => new_z,
+                    Err(reason) => {
+                        reject(reason);
+                        continue;
+                    }
+                };
+                // a Z axis pen goes up and down with ordinary moves
+                if let (Some(new_z), Actuator::ZAxis) = (new_z, &pen.actuator) {
+                    pen_down = new_z == pen.down || (new_z != pen.up && pen_down);
+                }
+                z = new_z;
+                if dest != position {
+                    checked.movements.push(Movement {
+                        dest,
+                        pen_down,
+                        ..Default::default()
+                    });
+                }
+                position = dest;
+            }
+            GCode::ClockwiseArc { target, center, .. }
+            | GCode::CounterClockwiseArc { target, center, .. } => {
+                let clockwise = matches!(op, GCode::ClockwiseArc { .. });
+                let dest = resolve(*target);
+                let center = match center {
+                    ArcCenter::Offset { i, j } => position + Vec2D { x: *i, y: *j },
+                    ArcCenter::Radius(radius) => {
+                        arcs::center_from_radius(position, dest, *radius, clockwise)
+                    }
+                };
+                let path =
+                    arcs::flatten(position, dest, center, clockwise, arcs::FLATTEN_TOLERANCE);
+                if let Some(off) = path.iter().find(|point| !on_bed(**point)) {
+                    reject(Reason::OffBed(*off));
+                    continue;
+                }
+                match height(*target) {
+                    Ok(new_z) => z = new_z,
+                    Err(reason) => {
+                        reject(reason);
+                        continue;
+                    }
+                }
+                checked.movements.push(Movement {
+                    dest,
+                    pen_down,
+                    arc: Some(ArcDescription { clockwise, center }),
+                    pen: None,
+                });
+                position = dest;
+            }
+            _ => (),
+        }
+        checked.gcode.push(op);
+    }
+
+    // the footer is written for absolute coordinates with no offset
+    if relative {
+        checked
+            .gcode
+            .push(GCode::SetPositionMode(Position::Absolute));
+    }
+    if offset != Vec2D::default() {
+        checked.gcode.push(GCode::SetCurrentPosition(Vec3 {
+            x: Some(position.x),
+            y: Some(position.y),
+            z: None,
+        }));
+    }
+
+    if rejections.is_empty() {
+        Ok(checked)
+    } else {
+        Err(rejections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn machine(flavor: &str) -> MachineDetails {
+        let settings: HashMap<String, String> = [
+            ("flavor", flavor),
+            ("xdim", "200"),
+            ("ydim", "100"),
+            ("name", "test"),
+            ("port", "/dev/null"),
+            ("baud_rate", "115200"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        settings.into()
+    }
+
+    fn reasons(source: &str, machine: &MachineDetails) -> Vec<Reason> {
+        match check(source, machine) {
+            Ok(_) => Vec::new(),
+            Err(rejections) => rejections.into_iter().map(|r| r.reason).collect(),
+        }
+    }
+
+    #[test]
+    fn unparsed_parameters_are_rejected() {
+        for flavor in ["GRBL", "Marlin"] {
+            let machine = machine(flavor);
+            for source in ["G1 X5000 Y5000 E1", "G1 X10 S1000", "M0 X5000"] {
+                assert_eq!(
+                    reasons(source, &machine),
+                    vec![Reason::Unchecked],
+                    "{flavor}: {source}"
+                );
+            }
+            assert_eq!(reasons("M0\nG1 X10 Y10", &machine), vec![]);
+        }
+    }
+
+    #[test]
+    fn pen_moves_are_whole_lines() {
+        let grbl = machine("GRBL");
+        assert_eq!(reasons("M3 S254\nM3 S65", &grbl), vec![]);
+        assert_eq!(
+            reasons("M3 S254 X5000", &grbl),
+            vec![Reason::NotAllowed("M3".to_string())]
+        );
+        assert_eq!(
+            reasons("M3 S1000", &grbl),
+            vec![Reason::NotAllowed("M3".to_string())]
+        );
+    }
+
+    #[test]
+    fn homing_by_way_of_a_point_is_rejected() {
+        assert_eq!(reasons("G28 X Y", &machine("Marlin")), vec![]);
+        for flavor in ["GRBL", "Marlin"] {
+            assert_eq!(
+                reasons("G28 X-500", &machine(flavor)),
+                vec![Reason::Unchecked],
+                "{flavor}"
+            );
+        }
+        // GRBL's G28 goes wherever G28.1 last stored
+        assert_eq!(reasons("G28", &machine("GRBL")), vec![Reason::Unchecked]);
+    }
+
+    #[test]
+    fn z_stays_within_the_pens() {
+        let marlin = machine("Marlin");
+        assert_eq!(reasons("G0 Z5\nG1 X10 Z0\nG0 Z2.5", &marlin), vec![]);
+        assert_eq!(reasons("G0 Z50", &marlin), vec![Reason::OffPen(50.0)]);
+        assert_eq!(
+            reasons("G0 Z5\nG91\nG1 Z-6", &marlin),
+            vec![Reason::OffPen(-1.0)]
+        );
+        // relative to a height that was never set
+        assert_eq!(reasons("G91\nG1 Z-1", &marlin), vec![Reason::Unchecked]);
+        assert_eq!(reasons("G92 Z10", &marlin), vec![Reason::Unchecked]);
+        // a servo pen has no business moving Z at all
+        assert_eq!(
+            reasons("G0 Z1", &machine("GRBL")),
+            vec![Reason::OffPen(1.0)]
+        );
+    }
+
+    #[test]
+    fn mode_and_offset_are_undone() {
+        let grbl = machine("GRBL");
+        let checked = check("G92 X10 Y10\nG91\nG1 X5 Y5", &grbl).unwrap();
+        assert_eq!(
+            checked.gcode[checked.gcode.len() - 2..],
+            [
+                GCode::SetPositionMode(Position::Absolute),
+                GCode::SetCurrentPosition(Vec3 {
+                    x: Some(5.0),
+                    y: Some(5.0),
+                    z: None,
+                }),
+            ]
+        );
+
+        let checked = check("G1 X5 Y5", &grbl).unwrap();
+        assert_eq!(checked.gcode.len(), 1);
+    }
+
+    #[test]
+    fn inches_become_millimetres() {
+        let grbl = machine("GRBL");
+        let checked = check("G20\nG1 X1 Y2 F10", &grbl).unwrap();
+        assert_eq!(
+            checked.gcode,
+            vec![
+                GCode::SetUnits(Units::Millimeters),
+                GCode::LinearDraw {
+                    target: Vec3 {
+                        x: Some(25.4),
+                        y: Some(50.8),
+                        z: None,
+                    },
+                    feedrate: Some(254),
+                },
+            ]
+        );
+        assert_eq!(checked.movements[0].dest, Vec2D { x: 25.4, y: 50.8 });
+
+        assert_eq!(
+            reasons("G20\nG0 X10", &grbl),
+            vec![Reason::OffBed(Vec2D { x: 254.0, y: 0.0 })]
+        );
+    }
+
+    #[test]
+    fn arcs_are_checked_all_the_way_round() {
+        let grbl = machine("GRBL");
+        assert_eq!(reasons("G0 X50 Y50\nG2 X70 Y50 I10 J0", &grbl), vec![]);
+        let rejected = reasons("G0 X50 Y5\nG3 X70 Y5 I10 J0", &grbl);
+        assert!(matches!(rejected[..], [Reason::OffBed(point)] if point.y < 0.0));
+    }
+}
@@ -13,6 +13,13 @@ pub enum PortCmd {
     STOP,
 }
 
+type Channels = (
+    Sender<String>,
+    Receiver<String>,
+    Sender<PortCmd>,
+    SerialChannel,
+);
+
 pub struct SerialChannel {
     _sender: Sender<String>,
     receiver: Receiver<String>,
@@ -22,10 +29,7 @@ pub struct SerialChannel {
 }
 
 impl SerialChannel {
-    pub fn new(
-        port_name: &str,
-        baud_rate: u32,
-    ) -> Result<(Sender<String>, Receiver<String>, Sender<PortCmd>, Self), Error> {
+    pub fn new(port_name: &str, baud_rate: u32) -> Result<Channels, Error> {
         match serialport::new(port_name, baud_rate).open() {
             Ok(port) => {
                 let (inbound_tx, inbound_rx) = mpsc::channel(1024);
@@ -41,7 +45,7 @@ impl SerialChannel {
                         receiver: inbound_rx,
                         command: cmd_rx,
                         status: PortCmd::RUN,
-                        port: port,
+                        port,
                     },
                 ))
             }
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use crate::arcs;
+use crate::models::{PenProfile, Vec2D};
+use crate::{ArcCenter, GCode, Position, Vec3};
+
+/// What a program does, worked out from its instructions without running it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    // lower left and upper right corners of everything drawn with the pen down
+    pub bounds: Option<(Vec2D, Vec2D)>,
+    pub draw_distance: f32,
+    pub travel_distance: f32,
+    pub duration: Duration,
+}
+
+impl Summary {
+    fn include(&mut self, point: Vec2D) {
+        self.bounds = Some(match self.bounds {
+            None => (point, point),
+            Some((low, high)) => (
+                Vec2D {
+                    x: low.x.min(point.x),
+                    y: low.y.min(point.y),
+                },
+                Vec2D {
+                    x: high.x.max(point.x),
+                    y: high.y.max(point.y),
+                },
+            ),
+        });
+    }
+}
+
+/// Walks the program tracking position and feedrate. Moves are assumed to run at
+/// their programmed F, which undersells rapids and ignores acceleration, so the
+/// duration is a rough guide rather than a promise.
+pub fn summarize(gcode: &[GCode], pen: &PenProfile) -> Summary {
+    let mut summary = Summary::default();
+    let mut position = Vec2D::default();
+    let mut mode = Position::Absolute;
+    let mut feedrate: Option<u32> = None;
+    let mut pen_down = false;
+    let mut seconds = 0.0;
+
+    let resolve = |position: Vec2D, target: &Vec3, mode: Position| match mode {
+        Position::Absolute => Vec2D {
+            x: target.x.unwrap_or(position.x),
+            y: target.y.unwrap_or(position.y),
+        },
+        Position::Relative => Vec2D {
+            x: position.x + target.x.unwrap_or(0.0),
+            y: position.y + target.y.unwrap_or(0.0),
+        },
+    };
+
+    for op in gcode {
+        let (points, rate) = match op {
+            GCode::LinearMove {
+                target,
+                feedrate: f,
+            }
+            | GCode::LinearDraw {
+                target,
+                feedrate: f,
+            } => (vec![resolve(position, target, mode)], *f),
+            GCode::ClockwiseArc {
+                target,
+                center,
+                feedrate: f,
+            }
+            | GCode::CounterClockwiseArc {
+                target,
+                center,
+                feedrate: f,
+            } => {
+                let end = resolve(position, target, mode);
+                let clockwise = matches!(op, GCode::ClockwiseArc { .. });
+                let center = match center {
+                    ArcCenter::Offset { i, j } => position + Vec2D { x: *i, y: *j },
+                    ArcCenter::Radius(r) => arcs::center_from_radius(position, end, *r, clockwise),
+                };
+                (
+                    arcs::flatten(position, end, center, clockwise, arcs::FLATTEN_TOLERANCE),
+                    *f,
+                )
+            }
+            GCode::Activate | GCode::Deactivate => {
+                pen_down = matches!(op, GCode::Activate);
+                seconds += pen.settle_ms as f32 / 1000.0;
+                continue;
+            }
+            GCode::Pause(ms) => {
+                seconds += *ms as f32 / 1000.0;
+                continue;
+            }
+            GCode::SetPositionMode(new_mode) => {
+                mode = *new_mode;
+                continue;
+            }
+            GCode::SetCurrentPosition(current) => {
+                position = Vec2D {
+                    x: current.x.unwrap_or(position.x),
+                    y: current.y.unwrap_or(position.y),
+                };
+                continue;
+            }
+            _ => continue,
+        };
+
+        feedrate = rate.or(feedrate);
+        if pen_down {
+            summary.include(position);
+        }
+        for point in points {
+            let length = position.distance(point);
+            if pen_down {
+                summary.draw_distance += length;
+                summary.include(point);
+            } else {
+                summary.travel_distance += length;
+            }
+            if let Some(feedrate) = feedrate.filter(|f| *f > 0) {
+                // feedrates are per minute
+                seconds += length / feedrate as f32 * 60.0;
+            }
+            position = point;
+        }
+    }
+
+    summary.duration = Duration::from_secs_f32(seconds);
+    summary
+}
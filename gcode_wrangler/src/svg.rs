@@ -0,0 +1,424 @@
+use std::fmt;
+use std::str::FromStr;
+
+use roxmltree::{Document, Node};
+use svgtypes::{Color, SimplePathSegment, SimplifyingPathParser, ViewBox};
+
+use crate::models::{Layer, Movement, PenProfile, Vec2D};
+use crate::transform::Transform;
+
+// how far, in mm, a flattened curve may stray from the real one unless told otherwise
+pub const CURVE_TOLERANCE: f32 = 0.1;
+
+// curves are split in half at most this many times, whatever the tolerance
+const MAX_SUBDIVISIONS: u32 = 16;
+
+// what elements are filled with when nothing says, as in SVG itself
+const DEFAULT_COLOR: Color = Color {
+    red: 0,
+    green: 0,
+    blue: 0,
+    alpha: 255,
+};
+
+// nothing in these is drawn where it is, if at all
+const SKIPPED: [&str; 10] = [
+    "defs", "clipPath", "mask", "marker", "pattern", "symbol", "style", "title", "desc", "metadata",
+];
+
+#[derive(Debug)]
+pub enum SvgError {
+    Xml(roxmltree::Error),
+    NotSvg,
+    // no viewBox, width or height to say how big the drawing is
+    NoSize,
+}
+
+impl fmt::Display for SvgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SvgError::Xml(e) => write!(f, "Not valid XML: {e}"),
+            SvgError::NotSvg => write!(f, "The root element isn't <svg>"),
+            SvgError::NoSize => write!(f, "The SVG needs a viewBox, or a width and height"),
+        }
+    }
+}
+
+impl std::error::Error for SvgError {}
+
+/// The colour as `#rrggbb`, which is how layers from an SVG are named
+pub fn color_name(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.red, color.green, color.blue)
+}
+
+/// The pen whose colour matches a layer name from `color_name`
+pub fn pen_for_color<'a>(pens: &'a [PenProfile], name: &str) -> Option<&'a PenProfile> {
+    pens.iter().find(|pen| {
+        pen.color
+            .as_deref()
+            .and_then(|color| Color::from_str(color).ok())
+            .is_some_and(|color| color_name(color) == name)
+    })
+}
+
+// Looks in the style attribute first, since it wins over presentation attributes
+fn property<'a>(node: &Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attribute("style")
+        .and_then(|style| {
+            style.split(';').find_map(|declaration| {
+                let (key, value) = declaration.split_once(':')?;
+                (key.trim() == name).then(|| value.trim())
+            })
+        })
+        .or_else(|| node.attribute(name))
+}
+
+fn number(node: &Node, name: &str) -> f64 {
+    node.attribute(name)
+        .and_then(|value| svgtypes::Length::from_str(value).ok())
+        .map_or(0.0, |length| length.number)
+}
+
+fn from_svg(transform: svgtypes::Transform) -> Transform {
+    Transform {
+        a: transform.a as f32,
+        b: transform.b as f32,
+        c: transform.c as f32,
+        d: transform.d as f32,
+        e: transform.e as f32,
+        f: transform.f as f32,
+    }
+}
+
+// Path data for the basic shapes, so everything goes through the one path parser
+fn outline(node: &Node) -> Option<String> {
+    let n = |name: &str| number(node, name);
+    match node.tag_name().name() {
+        "path" => node.attribute("d").map(str::to_string),
+        "line" => Some(format!(
+            "M {} {} L {} {}",
+            n("x1"),
+            n("y1"),
+            n("x2"),
+            n("y2")
+        )),
+        "polyline" | "polygon" => {
+            let points: Vec<String> = svgtypes::PointsParser::from(node.attribute("points")?)
+                .map(|(x, y)| format!("{x} {y}"))
+                .collect();
+            let close = if node.tag_name().name() == "polygon" {
+                " Z"
+            } else {
+                ""
+            };
+            Some(format!("M {}{close}", points.join(" L ")))
+        }
+        "rect" => {
+            let (x, y, w, h) = (n("x"), n("y"), n("width"), n("height"));
+            // either radius stands in for a missing other one
+            let (rx, ry) = match (node.attribute("rx"), node.attribute("ry")) {
+                (None, None) => (0.0, 0.0),
+                (Some(_), None) => (n("rx"), n("rx")),
+                (None, Some(_)) => (n("ry"), n("ry")),
+                (Some(_), Some(_)) => (n("rx"), n("ry")),
+            };
+            let (rx, ry) = (rx.min(w / 2.0), ry.min(h / 2.0));
+            if rx > 0.0 && ry > 0.0 {
+                Some(format!(
+                    "M {} {y} H {} A {rx} {ry} 0 0 1 {} {} V {} A {rx} {ry} 0 0 1 {} {} H {} \
+                     A {rx} {ry} 0 0 1 {x} {} V {} A {rx} {ry} 0 0 1 {} {y} Z",
+                    x + rx,
+                    x + w - rx,
+                    x + w,
+                    y + ry,
+                    y + h - ry,
+                    x + w - rx,
+                    y + h,
+                    x + rx,
+                    y + h - ry,
+                    y + ry,
+                    x + rx,
+                ))
+            } else {
+                Some(format!("M {x} {y} H {} V {} H {x} Z", x + w, y + h))
+            }
+        }
+        "circle" | "ellipse" => {
+            let (cx, cy) = (n("cx"), n("cy"));
+            let (rx, ry) = if node.tag_name().name() == "circle" {
+                (n("r"), n("r"))
+            } else {
+                (n("rx"), n("ry"))
+            };
+            Some(format!(
+                "M {} {cy} A {rx} {ry} 0 1 0 {} {cy} A {rx} {ry} 0 1 0 {} {cy} Z",
+                cx - rx,
+                cx + rx,
+                cx - rx
+            ))
+        }
+        _ => None,
+    }
+}
+
+// Splits a curve in half until each piece is flat enough, adding the ends of the
+// pieces to `points`. `controls` includes both ends.
+fn flatten(controls: &[Vec2D], tolerance: f32, depth: u32, points: &mut Vec<Vec2D>) {
+    let (start, end) = (controls[0], controls[controls.len() - 1]);
+    let chord = end - start;
+    let length = chord.length();
+    let flat = controls[1..controls.len() - 1].iter().all(|p| {
+        let offset = *p - start;
+        let away = if length > f32::EPSILON {
+            offset.cross(chord).abs() / length
+        } else {
+            offset.length()
+        };
+        away <= tolerance
+    });
+    if flat || depth >= MAX_SUBDIVISIONS {
+        points.push(end);
+        return;
+    }
+
+    // de Casteljau at the middle
+    let mut left: Vec<Vec2D> = vec![start];
+    let mut right: Vec<Vec2D> = vec![end];
+    let mut level: Vec<Vec2D> = controls.to_vec();
+    while level.len() > 1 {
+        level = level
+            .windows(2)
+            .map(|pair| Vec2D {
+                x: (pair[0].x + pair[1].x) / 2.0,
+                y: (pair[0].y + pair[1].y) / 2.0,
+            })
+            .collect();
+        left.push(level[0]);
+        right.push(level[level.len() - 1]);
+    }
+    right.reverse();
+    flatten(&left, tolerance, depth + 1, points);
+    flatten(&right, tolerance, depth + 1, points);
+}
+
+// Absolute movements for path data, already placed on the bed with `transform`
+fn trace(data: &str, transform: &Transform, tolerance: f32) -> Vec<Movement> {
+    let mut movements: Vec<Movement> = Vec::new();
+    let mut position = Vec2D::default();
+    let mut subpath_start = Vec2D::default();
+    let point = |x: f64, y: f64| {
+        transform.apply(Vec2D {
+            x: x as f32,
+            y: y as f32,
+        })
+    };
+    let draw = |dest: Vec2D| Movement {
+        dest,
+        pen_down: true,
+        ..Default::default()
+    };
+
+    // draws what it can of broken path data, as browsers do
+    for segment in SimplifyingPathParser::from(data).map_while(Result::ok) {
+        match segment {
+            SimplePathSegment::MoveTo { x, y } => {
+                position = point(x, y);
+                subpath_start = position;
+                movements.push(Movement {
+                    dest: position,
+                    pen_down: false,
+                    ..Default::default()
+                });
+            }
+            SimplePathSegment::LineTo { x, y } => {
+                position = point(x, y);
+                movements.push(draw(position));
+            }
+            SimplePathSegment::Quadratic { x1, y1, x, y } => {
+                let mut points: Vec<Vec2D> = Vec::new();
+                flatten(
+                    &[position, point(x1, y1), point(x, y)],
+                    tolerance,
+                    0,
+                    &mut points,
+                );
+                movements.extend(points.into_iter().map(draw));
+                position = point(x, y);
+            }
+            SimplePathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => {
+                let mut points: Vec<Vec2D> = Vec::new();
+                flatten(
+                    &[position, point(x1, y1), point(x2, y2), point(x, y)],
+                    tolerance,
+                    0,
+                    &mut points,
+                );
+                movements.extend(points.into_iter().map(draw));
+                position = point(x, y);
+            }
+            SimplePathSegment::ClosePath => {
+                position = subpath_start;
+                movements.push(draw(position));
+            }
+        }
+    }
+
+    movements
+}
+
+// Where the drawing's coordinates are meant to be seen, from the root element
+fn view_box(root: &Node) -> Option<ViewBox> {
+    if let Some(view_box) = root
+        .attribute("viewBox")
+        .and_then(|value| ViewBox::from_str(value).ok())
+    {
+        return Some(view_box);
+    }
+    let (width, height) = (number(root, "width"), number(root, "height"));
+    (width > 0.0 && height > 0.0).then(|| ViewBox::new(0.0, 0.0, width, height))
+}
+
+// what an element is painted with, inherited from its parents where it doesn't say;
+// None where it's "none"
+#[derive(Clone, Copy)]
+struct Paint {
+    stroke: Option<Color>,
+    fill: Option<Color>,
+}
+
+struct Walker {
+    tolerance: f32,
+    // colour names in the order they first turn up, with what's drawn in them
+    layers: Vec<(String, Vec<Movement>)>,
+}
+
+impl Walker {
+    fn walk(&mut self, node: Node, parent: &Transform, inherited: Paint) {
+        if !node.is_element()
+            || SKIPPED.contains(&node.tag_name().name())
+            || property(&node, "display") == Some("none")
+        {
+            return;
+        }
+
+        let transform = node
+            .attribute("transform")
+            .and_then(|value| svgtypes::Transform::from_str(value).ok())
+            .map_or(*parent, |own| from_svg(own).then(parent));
+        let paint = |name: &str, parent: Option<Color>| match property(&node, name) {
+            Some("none") => None,
+            Some(value) => Color::from_str(value).ok().or(parent),
+            None => parent,
+        };
+        let paint = Paint {
+            stroke: paint("stroke", inherited.stroke),
+            fill: paint("fill", inherited.fill),
+        };
+        // the stroke is what gets drawn, but filled shapes without one still have edges,
+        // and anything with neither can't be seen
+        let color = paint.stroke.or(paint.fill);
+
+        if let Some((data, color)) = outline(&node).zip(color) {
+            let traced = trace(&data, &transform, self.tolerance);
+            if !traced.is_empty() {
+                let name = color_name(color);
+                match self.layers.iter_mut().find(|(layer, _)| *layer == name) {
+                    Some((_, movements)) => movements.extend(traced),
+                    None => self.layers.push((name, traced)),
+                }
+            }
+        }
+
+        for child in node.children() {
+            self.walk(child, &transform, paint);
+        }
+    }
+}
+
+/// Reads an SVG's paths and shapes into absolute movements on a bed of `dimensions`,
+/// with a layer for each colour they're drawn in. The viewBox is scaled to fit the
+/// bed and centred on it, and curves are flattened to within `tolerance` mm. Nested
+/// `<svg>` elements are treated as groups, and `<use>` and text aren't drawn.
+pub fn import(source: &str, dimensions: Vec2D, tolerance: f32) -> Result<Vec<Layer>, SvgError> {
+    let document = Document::parse(source).map_err(SvgError::Xml)?;
+    let root = document.root_element();
+    if root.tag_name().name() != "svg" {
+        return Err(SvgError::NotSvg);
+    }
+    let view_box = view_box(&root).ok_or(SvgError::NoSize)?;
+
+    // SVG's Y axis points down the page, and the bed's points away from the origin
+    let scale = (dimensions.x / view_box.w as f32).min(dimensions.y / view_box.h as f32);
+    let placed = Transform::translate(
+        -(view_box.x + view_box.w / 2.0) as f32,
+        -(view_box.y + view_box.h / 2.0) as f32,
+    )
+    .then(&Transform::scale(scale, -scale))
+    .then(&Transform::translate(
+        dimensions.x / 2.0,
+        dimensions.y / 2.0,
+    ));
+
+    let mut walker = Walker {
+        tolerance,
+        layers: Vec::new(),
+    };
+    walker.walk(
+        root,
+        &placed,
+        Paint {
+            stroke: None,
+            fill: Some(DEFAULT_COLOR),
+        },
+    );
+
+    Ok(walker
+        .layers
+        .into_iter()
+        .map(|(name, movements)| Layer::new(&name, movements))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer_names(source: &str) -> Vec<String> {
+        import(source, Vec2D { x: 100.0, y: 100.0 }, 0.1)
+            .unwrap()
+            .into_iter()
+            .map(|layer| layer.name)
+            .collect()
+    }
+
+    #[test]
+    fn inherited_strokes_beat_own_fills() {
+        let names = layer_names(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">
+                <g stroke="red"><rect width="5" height="5" fill="blue"/></g>
+            </svg>"#,
+        );
+        assert_eq!(names, ["#ff0000"]);
+    }
+
+    #[test]
+    fn unpainted_elements_are_skipped() {
+        let names = layer_names(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10">
+                <g stroke="red">
+                    <rect width="5" height="5" stroke="none" fill="none"/>
+                    <circle cx="5" cy="5" r="2" stroke="none"/>
+                </g>
+            </svg>"#,
+        );
+        // the circle is still filled with the default black
+        assert_eq!(names, ["#000000"]);
+    }
+}
@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+
+use crate::arcs;
+use crate::models::{ArcDescription, Movement, Vec2D};
+
+/// An affine transform in the same layout as SVG's `matrix(a b c d e f)`:
+/// x' = a x + c y + e, y' = b x + d y + f
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Transform {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+    pub e: f32,
+    pub f: f32,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform {
+            a: 1.0,
+            b: 0.0,
+            c: 0.0,
+            d: 1.0,
+            e: 0.0,
+            f: 0.0,
+        }
+    }
+
+    pub fn translate(x: f32, y: f32) -> Self {
+        Transform {
+            e: x,
+            f: y,
+            ..Transform::identity()
+        }
+    }
+
+    pub fn scale(x: f32, y: f32) -> Self {
+        Transform {
+            a: x,
+            d: y,
+            ..Transform::identity()
+        }
+    }
+
+    /// Counter-clockwise, in degrees
+    pub fn rotate(degrees: f32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Transform {
+            a: cos,
+            b: sin,
+            c: -sin,
+            d: cos,
+            ..Transform::identity()
+        }
+    }
+
+    /// `self` followed by `next`
+    pub fn then(&self, next: &Transform) -> Transform {
+        Transform {
+            a: next.a * self.a + next.c * self.b,
+            b: next.b * self.a + next.d * self.b,
+            c: next.a * self.c + next.c * self.d,
+            d: next.b * self.c + next.d * self.d,
+            e: next.a * self.e + next.c * self.f + next.e,
+            f: next.b * self.e + next.d * self.f + next.f,
+        }
+    }
+
+    /// `self`, but happening around `center` rather than the origin
+    pub fn around(&self, center: Vec2D) -> Transform {
+        Transform::translate(-center.x, -center.y)
+            .then(self)
+            .then(&Transform::translate(center.x, center.y))
+    }
+
+    pub fn apply(&self, point: Vec2D) -> Vec2D {
+        Vec2D {
+            x: self.a * point.x + self.c * point.y + self.e,
+            y: self.b * point.x + self.d * point.y + self.f,
+        }
+    }
+
+    // a mirror image runs arcs the other way round
+    fn flips(&self) -> bool {
+        self.a * self.d - self.b * self.c < 0.0
+    }
+
+    // whether circles stay circles, so arcs can be kept as arcs
+    fn keeps_circles(&self) -> bool {
+        let close = |x: f32, y: f32| (x - y).abs() <= 1e-4 * (x.abs() + y.abs()).max(1.0);
+        (close(self.a, self.d) && close(self.b, -self.c))
+            || (close(self.a, -self.d) && close(self.b, self.c))
+    }
+
+    /// Transforms absolute movements starting from the origin. Arcs that the transform
+    /// would stretch into ellipses are broken into straight segments first.
+    pub fn apply_movements(&self, movements: &[Movement]) -> Vec<Movement> {
+        let mut transformed: Vec<Movement> = Vec::with_capacity(movements.len());
+        let mut position = Vec2D::default();
+
+        for mv in movements {
+            match mv.arc {
+                Some(arc) if !self.keeps_circles() => {
+                    for point in arcs::flatten(
+                        position,
+                        mv.dest,
+                        arc.center,
+                        arc.clockwise,
+                        arcs::FLATTEN_TOLERANCE,
+                    ) {
+                        transformed.push(Movement {
+                            dest: self.apply(point),
+                            arc: None,
+                            ..mv.clone()
+                        });
+                    }
+                }
+                _ => transformed.push(Movement {
+                    dest: self.apply(mv.dest),
+                    arc: mv.arc.map(|arc| ArcDescription {
+                        clockwise: arc.clockwise != self.flips(),
+                        center: self.apply(arc.center),
+                    }),
+                    ..mv.clone()
+                }),
+            }
+            position = mv.dest;
+        }
+
+        transformed
+    }
+}
+
+/// Lower left and upper right corners of everything drawn, or of every point visited
+/// if nothing is. Movements are absolute, starting from the origin.
+pub fn bounds<'a>(movements: impl IntoIterator<Item = &'a Movement>) -> Option<(Vec2D, Vec2D)> {
+    let mut drawn: Vec<Vec2D> = Vec::new();
+    let mut visited: Vec<Vec2D> = Vec::new();
+    let mut position = Vec2D::default();
+
+    for mv in movements {
+        if mv.pen_down {
+            drawn.push(position);
+            match mv.arc {
+                Some(arc) => drawn.extend(arcs::flatten(
+                    position,
+                    mv.dest,
+                    arc.center,
+                    arc.clockwise,
+                    arcs::FLATTEN_TOLERANCE,
+                )),
+                None => drawn.push(mv.dest),
+            }
+        }
+        visited.push(mv.dest);
+        position = mv.dest;
+    }
+
+    let points = if drawn.is_empty() { visited } else { drawn };
+    let first = *points.first()?;
+    Some(points.iter().fold((first, first), |(low, high), p| {
+        (
+            Vec2D {
+                x: low.x.min(p.x),
+                y: low.y.min(p.y),
+            },
+            Vec2D {
+                x: high.x.max(p.x),
+                y: high.y.max(p.y),
+            },
+        )
+    }))
+}
+
+/// Scales `bounds` up or down to fill the bed less `margin` on every side, keeping its
+/// aspect ratio, and centres it. With `auto_rotate`, it's turned a quarter turn first
+/// if that lets it come out bigger.
+pub fn fit_to_bed(
+    bounds: (Vec2D, Vec2D),
+    dimensions: Vec2D,
+    margin: f32,
+    auto_rotate: bool,
+) -> Transform {
+    let (low, high) = bounds;
+    let size = high - low;
+    let center = Vec2D {
+        x: (low.x + high.x) / 2.0,
+        y: (low.y + high.y) / 2.0,
+    };
+    let room = Vec2D {
+        x: (dimensions.x - 2.0 * margin).max(0.0),
+        y: (dimensions.y - 2.0 * margin).max(0.0),
+    };
+
+    // a point or a line has no size along some axis, and fits any amount that way
+    let fit = |width: f32, height: f32| {
+        let x = if width > 0.0 {
+            room.x / width
+        } else {
+            f32::INFINITY
+        };
+        let y = if height > 0.0 {
+            room.y / height
+        } else {
+            f32::INFINITY
+        };
+        let scale = x.min(y);
+        if scale.is_finite() {
+            scale
+        } else {
+            1.0
+        }
+    };
+    let upright = fit(size.x, size.y);
+    let turned = fit(size.y, size.x);
+
+    let rotation = if auto_rotate && turned > upright {
+        Transform::rotate(90.0)
+    } else {
+        Transform::identity()
+    };
+    let scale = if auto_rotate {
+        upright.max(turned)
+    } else {
+        upright
+    };
+
+    Transform::translate(-center.x, -center.y)
+        .then(&rotation)
+        .then(&Transform::scale(scale, scale))
+        .then(&Transform::translate(
+            dimensions.x / 2.0,
+            dimensions.y / 2.0,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec2D, b: Vec2D) -> bool {
+        a.distance(b) < 1e-4
+    }
+
+    #[test]
+    fn composed_transforms_apply_in_order() {
+        let point = Vec2D { x: 1.0, y: 1.0 };
+        let scaled_then_moved = Transform::scale(2.0, 2.0).then(&Transform::translate(1.0, 0.0));
+        let moved_then_scaled = Transform::translate(1.0, 0.0).then(&Transform::scale(2.0, 2.0));
+        assert!(close(
+            scaled_then_moved.apply(point),
+            Vec2D { x: 3.0, y: 2.0 }
+        ));
+        assert!(close(
+            moved_then_scaled.apply(point),
+            Vec2D { x: 4.0, y: 2.0 }
+        ));
+
+        let turned = Transform::rotate(90.0).around(Vec2D { x: 1.0, y: 1.0 });
+        assert!(close(
+            turned.apply(Vec2D { x: 2.0, y: 1.0 }),
+            Vec2D { x: 1.0, y: 2.0 }
+        ));
+    }
+
+    #[test]
+    fn mirroring_reverses_arcs() {
+        let arc = Movement {
+            dest: Vec2D { x: 2.0, y: 0.0 },
+            pen_down: true,
+            arc: Some(ArcDescription {
+                clockwise: true,
+                center: Vec2D { x: 1.0, y: 0.0 },
+            }),
+            ..Default::default()
+        };
+        let mirrored = Transform::scale(-1.0, 1.0).apply_movements(&[arc]);
+        let mirrored_arc = mirrored[0].arc.unwrap();
+        assert!(!mirrored_arc.clockwise);
+        assert!(close(mirrored_arc.center, Vec2D { x: -1.0, y: 0.0 }));
+    }
+
+    #[test]
+    fn fit_to_bed_fills_the_room_and_centres() {
+        let dimensions = Vec2D { x: 200.0, y: 100.0 };
+        let bounds = (Vec2D { x: 10.0, y: 10.0 }, Vec2D { x: 30.0, y: 20.0 });
+        let fitted = fit_to_bed(bounds, dimensions, 10.0, false);
+        assert!(close(fitted.apply(bounds.0), Vec2D { x: 20.0, y: 10.0 }));
+        assert!(close(fitted.apply(bounds.1), Vec2D { x: 180.0, y: 90.0 }));
+
+        // a tall drawing comes out twice as big turned on its side
+        let tall = (Vec2D { x: 0.0, y: 0.0 }, Vec2D { x: 10.0, y: 40.0 });
+        let upright = fit_to_bed(tall, dimensions, 0.0, false);
+        let turned = fit_to_bed(tall, dimensions, 0.0, true);
+        assert!(close(
+            upright.apply(tall.1) - upright.apply(tall.0),
+            Vec2D { x: 25.0, y: 100.0 }
+        ));
+        assert!(close(
+            turned.apply(tall.1) - turned.apply(tall.0),
+            Vec2D { x: -200.0, y: 50.0 }
+        ));
+    }
+}